@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::io::{self, Stdout};
+use std::time::{Duration, SystemTime};
 
 use crossterm::event::{
     DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyModifiers,
@@ -8,14 +9,47 @@ use crossterm::execute;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
-use futures::{Future, Stream, StreamExt};
+use futures::{Future, Sink, Stream, StreamExt};
 use ratatui::backend::CrosstermBackend;
-use ratatui::widgets::{Block, BorderType, Borders};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, BorderType, Borders, Paragraph};
 use ratatui::{Frame, Terminal};
-use tilia_widget::{BoxedError, BytesMut, LogView};
+use tilia_widget::protocol::{AppInfo, Around, Command, Reply};
+use tilia_widget::record::{Level, Record};
+use tilia_widget::{BoxedError, Bytes, BytesMut, LogEntry, LogView};
+use tokio::sync::mpsc;
+
+// Events fetched from the flight recorder on either side of the selected one
+const RECORDED_CONTEXT: usize = 50;
 
 pub struct Console<'a> {
     logs: LogView<'a>,
+    paused: bool,
+    input: Option<Input>,
+    status: Option<String>,
+    status_tx: mpsc::UnboundedSender<String>,
+    status_rx: mpsc::UnboundedReceiver<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Prompt {
+    ServerFilter,
+    CaptureFilter,
+}
+
+impl Prompt {
+    fn title(&self) -> &'static str {
+        match self {
+            Self::ServerFilter => "Server filter",
+            Self::CaptureFilter => "Capture filter",
+        }
+    }
+}
+
+struct Input {
+    prompt: Prompt,
+    text: String,
 }
 
 impl<'a> Console<'a> {
@@ -23,16 +57,22 @@ impl<'a> Console<'a> {
     where
         F: Fn() -> Fut + Clone + Send + Sync + 'static,
         Fut: Future<Output = Result<S, BoxedError>> + Send,
-        S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-        E: Error + Send + Sync,
+        S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+        E: Error + Send + Sync + 'static,
     {
-        Self {
-            logs: LogView::new(make_transport),
-        }
+        Self::from_log_view(LogView::new(make_transport))
     }
 
     pub fn from_log_view(log_view: LogView<'a>) -> Self {
-        Self { logs: log_view }
+        let (status_tx, status_rx) = mpsc::unbounded_channel();
+        Self {
+            logs: log_view,
+            paused: false,
+            input: None,
+            status: None,
+            status_tx,
+            status_rx,
+        }
     }
 
     pub async fn run(&mut self) -> Result<(), BoxedError> {
@@ -70,10 +110,15 @@ impl<'a> Console<'a> {
             terminal.draw(|f| self.ui(f))?;
             tokio::select! {
                 _ = self.logs.update() => {}
+                Some(status) = self.status_rx.recv() => self.status = Some(status),
                 maybe_event = event_reader.next() => {
                     match maybe_event {
                         Some(Ok(event)) => {
                             if let Event::Key(key) = event {
+                                if self.input.is_some() {
+                                    self.handle_input(key.code);
+                                    continue;
+                                }
                                 match (key.modifiers, key.code) {
                                     (_, KeyCode::Char('q') | KeyCode::Esc) |
                                         (KeyModifiers::CONTROL, KeyCode::Char('c')) => {
@@ -81,6 +126,20 @@ impl<'a> Console<'a> {
                                         },
                                     (_, KeyCode::Down) =>  self.logs.next(),
                                     (_, KeyCode::Up) =>  self.logs.previous(),
+                                    (_, KeyCode::Char('l')) => self.cycle_min_level()?,
+                                    (_, KeyCode::Char('p')) => self.toggle_pause(),
+                                    (_, KeyCode::Char('x')) => self.toggle_hex_dump()?,
+                                    (_, KeyCode::Char('h')) => self.send_command(Command::History),
+                                    (_, KeyCode::Char('d')) => self.fetch_recorded(),
+                                    (_, KeyCode::Char('r')) => {
+                                        self.send_command(Command::ReplaySpill)
+                                    }
+                                    (_, KeyCode::Char('f')) => {
+                                        self.open_prompt(Prompt::ServerFilter)
+                                    }
+                                    (_, KeyCode::Char('c')) => {
+                                        self.open_prompt(Prompt::CaptureFilter)
+                                    }
                                     _ => {}
                                 }
                             }
@@ -94,12 +153,186 @@ impl<'a> Console<'a> {
         }
     }
 
+    fn cycle_min_level(&mut self) -> Result<(), BoxedError> {
+        let filter = self.logs.filter().clone();
+        let min_level = match filter.min_level {
+            None | Some(Level::Trace) => Some(Level::Debug),
+            Some(Level::Debug) => Some(Level::Info),
+            Some(Level::Info) => Some(Level::Warn),
+            Some(Level::Warn) => Some(Level::Error),
+            Some(Level::Error) => None,
+        };
+        self.logs.set_filter(filter.with_min_level(min_level))?;
+        Ok(())
+    }
+
+    fn toggle_hex_dump(&mut self) -> Result<(), BoxedError> {
+        let hex_dump = !self.logs.hex_dump();
+        self.logs.set_hex_dump(hex_dump)?;
+        self.status = Some(if hex_dump {
+            "Showing binary payloads as hex".to_owned()
+        } else {
+            "Showing binary payloads as text".to_owned()
+        });
+        Ok(())
+    }
+
+    fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.send_command(if self.paused {
+            Command::Pause
+        } else {
+            Command::Resume
+        });
+    }
+
+    fn fetch_recorded(&mut self) {
+        match self.logs.selected() {
+            Some(
+                LogEntry::Record(Record::Event(event)) | LogEntry::Recorded(Record::Event(event)),
+            ) => {
+                self.send_command(Command::Recorded {
+                    around: Around::Time(event.timestamp),
+                    count: RECORDED_CONTEXT,
+                });
+            }
+            _ => self.status = Some("Select an event to fetch its details".to_owned()),
+        }
+    }
+
+    fn open_prompt(&mut self, prompt: Prompt) {
+        self.input = Some(Input {
+            prompt,
+            text: String::new(),
+        });
+    }
+
+    fn handle_input(&mut self, code: KeyCode) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+        match code {
+            KeyCode::Char(c) => input.text.push(c),
+            KeyCode::Backspace => {
+                input.text.pop();
+            }
+            KeyCode::Esc => self.input = None,
+            KeyCode::Enter => {
+                let Some(input) = self.input.take() else {
+                    return;
+                };
+                // An empty filter clears any previously set directives
+                let directives = Some(input.text.trim().to_owned()).filter(|d| !d.is_empty());
+                self.send_command(match input.prompt {
+                    Prompt::ServerFilter => Command::SetFilter(directives),
+                    Prompt::CaptureFilter => Command::SetCaptureFilter(directives),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn send_command(&self, command: Command) {
+        let commands = self.logs.command_sender();
+        let status_tx = self.status_tx.clone();
+        tokio::spawn(async move {
+            let status = match commands.send(command.clone()).await {
+                Ok(Reply::Error(e)) => format!("{command:?} failed: {e}"),
+                Ok(_) => return,
+                Err(e) => format!("{command:?} failed: {e}"),
+            };
+            let _ = status_tx.send(status);
+        });
+    }
+
     fn ui(&mut self, f: &mut Frame) {
         let size = f.area();
         let block = Block::default()
             .borders(Borders::all())
             .border_type(BorderType::Rounded);
         f.render_widget(block, size);
-        self.logs.render(f, size);
+
+        let footer_height = match (&self.input, &self.status) {
+            (Some(_), _) => 3,
+            (None, Some(_)) => 1,
+            (None, None) => 0,
+        };
+        let header_height = if self.logs.app_info().is_some() { 1 } else { 0 };
+        let [header_area, logs_area, footer_area] = Layout::vertical([
+            Constraint::Length(header_height),
+            Constraint::Min(0),
+            Constraint::Length(footer_height),
+        ])
+        .areas(size);
+        if let Some(info) = self.logs.app_info() {
+            let header = Paragraph::new(header_text(info))
+                .style(Style::default().add_modifier(Modifier::REVERSED));
+            f.render_widget(header, header_area);
+        }
+        self.logs.render(f, logs_area);
+
+        if let Some(input) = &self.input {
+            let prompt = Paragraph::new(format!("{}_", input.text)).block(
+                Block::default()
+                    .borders(Borders::all())
+                    .border_type(BorderType::Rounded)
+                    .title(input.prompt.title()),
+            );
+            f.render_widget(prompt, footer_area);
+        } else if let Some(status) = &self.status {
+            f.render_widget(Paragraph::new(status.as_str()), footer_area);
+        }
+    }
+}
+
+// Enough to tell apart several services running on the same machine
+fn header_text(info: &AppInfo) -> String {
+    let mut parts = vec![match (&info.name, &info.version) {
+        (Some(name), Some(version)) => format!("{name} {version}"),
+        (Some(name), None) => name.clone(),
+        (None, Some(version)) => format!("unknown app {version}"),
+        (None, None) => "unknown app".to_owned(),
+    }];
+    parts.push(format!("pid {}", info.pid));
+    if let Some(hostname) = &info.hostname {
+        parts.push(format!("on {hostname}"));
+    }
+    if let Some(uptime) = info
+        .started_at
+        .and_then(|started_at| SystemTime::now().duration_since(started_at).ok())
+    {
+        parts.push(format!("up {}", format_uptime(uptime)));
+    }
+    parts.push(format!(
+        "history {} events ({})",
+        info.history_entries,
+        format_bytes(info.history_bytes)
+    ));
+    parts.push(format!("tilia {}", info.tilia_version));
+    format!(" {}", parts.join(" | "))
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
     }
 }
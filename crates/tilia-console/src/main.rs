@@ -1,8 +1,10 @@
-use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
 use tilia_console::Console;
 use tilia_widget::transport::docker::{self, docker_client};
-use tilia_widget::transport::{ipc_client, tcp_client};
-use tilia_widget::BoxedError;
+use tilia_widget::transport::{ipc_client, spill_file, tcp_client};
+use tilia_widget::{BoxedError, LogView, Secret};
 use transport_async::ipc::ServerId;
 
 #[derive(Clone, Debug, ValueEnum)]
@@ -12,7 +14,20 @@ pub enum ContainerLogSource {
     All,
 }
 
-#[derive(Clone, Debug, clap::Parser)]
+#[derive(Clone, Debug, Parser)]
+pub struct Cli {
+    // Only receive events matching these directives, e.g. `my_crate::db=debug,warn`
+    #[arg(long, global = true)]
+    filter: Option<String>,
+    // Needed to connect to apps that require a secret, prefer the environment variable so it
+    // doesn't show up in the process list
+    #[arg(long, global = true, env = "TILIA_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+    #[command(subcommand)]
+    transport: Tranport,
+}
+
+#[derive(Clone, Debug, Subcommand)]
 pub enum Tranport {
     Ipc {
         app_name: String,
@@ -24,30 +39,40 @@ pub enum Tranport {
         name: String,
         log_source: ContainerLogSource,
     },
+    // Read the files an app spilled its history to, or a dump in the replay format
+    File {
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), BoxedError> {
-    let cli = Tranport::parse();
+    let cli = Cli::parse();
+
+    macro_rules! run_console {
+        ($make_transport:expr) => {{
+            let mut builder = LogView::builder($make_transport);
+            if let Some(filter) = cli.filter {
+                builder = builder.with_server_filter(filter);
+            }
+            if let Some(secret) = cli.secret {
+                builder = builder.with_secret(Secret::new(secret)?);
+            }
+            Console::from_log_view(builder.build()).run().await
+        }};
+    }
 
-    match cli {
-        Tranport::Ipc { app_name } => {
-            Console::new(ipc_client(ServerId::new(app_name)))
-                .run()
-                .await
-        }
-        Tranport::Tcp { address } => Console::new(tcp_client(address)).run().await,
-        Tranport::Container { name, log_source } => {
-            Console::new(docker_client(
-                name,
-                match log_source {
-                    ContainerLogSource::Stdout => docker::LogSource::Stdout,
-                    ContainerLogSource::Stderr => docker::LogSource::Stderr,
-                    ContainerLogSource::All => docker::LogSource::All,
-                },
-            ))
-            .run()
-            .await
-        }
+    match cli.transport {
+        Tranport::Ipc { app_name } => run_console!(ipc_client(ServerId::new(app_name))),
+        Tranport::Tcp { address } => run_console!(tcp_client(address)),
+        Tranport::Container { name, log_source } => run_console!(docker_client(
+            name,
+            match log_source {
+                ContainerLogSource::Stdout => docker::LogSource::Stdout,
+                ContainerLogSource::Stderr => docker::LogSource::Stderr,
+                ContainerLogSource::All => docker::LogSource::All,
+            },
+        )),
+        Tranport::File { path } => run_console!(spill_file(path)),
     }
 }
@@ -1,51 +1,102 @@
+use std::collections::VecDeque;
 use std::error::Error;
 
-use ansi_to_tui::IntoText;
-use futures::{Future, Stream};
+use futures::{Future, Sink, Stream, future};
 use ratatui::Frame;
 use ratatui::layout::Rect;
-use ratatui::widgets::ListItem;
 use stateful_list::StatefulList;
-pub use tilia::{BoxedError, Bytes, BytesMut, run_client, transport};
+use tilia::protocol::{AppInfo, ProtocolError};
+use tilia::record::Record;
+pub use tilia::{
+    Backoff, BoxedError, Bytes, BytesMut, ClientEvent, ClientSettings, CommandError, CommandSender,
+    ConnectionState, Secret, command_channel, directive, protocol, record, run_client, transport,
+};
+use tokio::task::JoinHandle;
+mod log_filter;
 mod stateful_list;
+pub use log_filter::*;
+mod record_item;
 
 pub struct LogViewBuilder<F, S, E, Fut>
 where
     F: Fn() -> Fut + Clone + Send + Sync,
     Fut: Future<Output = Result<S, BoxedError>> + Send,
-    S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-    E: Error + Send + Sync,
+    S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+    E: Error + Send + Sync + 'static,
 {
     max_logs: usize,
     make_transport: F,
+    settings: ClientSettings,
 }
 
 impl<F, S, E, Fut> LogViewBuilder<F, S, E, Fut>
 where
     F: Fn() -> Fut + Clone + Send + Sync + 'static,
     Fut: Future<Output = Result<S, BoxedError>> + Send,
-    S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-    E: Error + Send + Sync,
+    S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+    E: Error + Send + Sync + 'static,
 {
     pub fn new(make_transport: F) -> Self {
         Self {
             make_transport,
             max_logs: 1024,
+            settings: ClientSettings::default(),
         }
     }
     pub fn with_max_logs(self, max_logs: usize) -> Self {
         Self { max_logs, ..self }
     }
 
+    pub fn with_server_filter(self, directives: impl Into<String>) -> Self {
+        Self {
+            settings: self.settings.with_filter(directives),
+            ..self
+        }
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        Self {
+            settings: self.settings.with_backoff(backoff),
+            ..self
+        }
+    }
+
+    pub fn with_secret(self, secret: Secret) -> Self {
+        Self {
+            settings: self.settings.with_secret(secret),
+            ..self
+        }
+    }
+
     pub fn build<'a>(self) -> LogView<'a> {
         LogView::from_builder(self)
     }
 }
 
+// What the log list shows, connection updates are kept separately
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogEntry {
+    Record(Record),
+    // Fetched from the flight recorder, not part of the live output
+    Recorded(Record),
+    // The server skipped this many events
+    Dropped(u64),
+}
+
 pub struct LogView<'a> {
-    rx: tokio::sync::mpsc::Receiver<String>,
+    rx: tokio::sync::mpsc::Receiver<ClientEvent>,
+    records: VecDeque<LogEntry>,
+    max_logs: usize,
+    filter: LogFilter,
+    // Show binary payloads as a hex dump instead of lossy text
+    hex_dump: bool,
     logs: StatefulList<'a>,
     log_stream_running: bool,
+    client: Option<JoinHandle<Result<(), ProtocolError>>>,
+    commands: CommandSender,
+    connection: ConnectionState,
+    app_info: Option<AppInfo>,
+    error: Option<ProtocolError>,
 }
 
 impl LogView<'_> {
@@ -53,8 +104,8 @@ impl LogView<'_> {
     where
         F: Fn() -> Fut + Clone + Send + Sync + 'static,
         Fut: Future<Output = Result<S, BoxedError>> + Send,
-        S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-        E: Error + Send + Sync,
+        S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+        E: Error + Send + Sync + 'static,
     {
         LogViewBuilder::new(make_transport)
     }
@@ -63,18 +114,28 @@ impl LogView<'_> {
     where
         F: Fn() -> Fut + Clone + Send + Sync + 'static,
         Fut: Future<Output = Result<S, BoxedError>> + Send,
-        S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-        E: Error + Send + Sync,
+        S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+        E: Error + Send + Sync + 'static,
     {
         let (tx, rx) = tokio::sync::mpsc::channel(32);
-        tokio::spawn(async move {
-            run_client(builder.make_transport, tx).await;
+        let (commands, commands_rx) = command_channel(32);
+        let client = tokio::spawn(async move {
+            run_client(builder.make_transport, builder.settings, tx, commands_rx).await
         });
 
         Self {
             rx,
+            records: VecDeque::new(),
+            max_logs: builder.max_logs,
+            filter: LogFilter::default(),
+            hex_dump: false,
             logs: StatefulList::new(builder.max_logs),
             log_stream_running: true,
+            client: Some(client),
+            commands,
+            connection: ConnectionState::Connecting,
+            app_info: None,
+            error: None,
         }
     }
 
@@ -82,26 +143,101 @@ impl LogView<'_> {
     where
         F: Fn() -> Fut + Clone + Send + Sync + 'static,
         Fut: Future<Output = Result<S, BoxedError>> + Send,
-        S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
-        E: Error + Send + Sync,
+        S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
+        E: Error + Send + Sync + 'static,
     {
         Self::builder(make_transport).build()
     }
 
     pub async fn update(&mut self) -> Result<(), ansi_to_tui::Error> {
         if self.log_stream_running {
-            let log = self.rx.recv().await;
-            if let Some(log) = log {
-                let text = ListItem::new(log.into_text()?);
-                self.logs.add_item(text);
+            let event = self.rx.recv().await;
+            if let Some(event) = event {
+                self.add_event(event)?;
                 // Drain all pending items to prevent slow updates
-                while let Ok(log) = self.rx.try_recv() {
-                    let text = ListItem::new(log.into_text()?);
-                    self.logs.add_item(text);
+                while let Ok(event) = self.rx.try_recv() {
+                    self.add_event(event)?;
                 }
             } else {
                 self.log_stream_running = false;
+                if let Some(client) = self.client.take() {
+                    if let Ok(Err(e)) = client.await {
+                        self.error = Some(e);
+                    }
+                }
+            }
+        } else {
+            // Nothing left to receive, don't let callers spin in a loop
+            future::pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    pub fn command_sender(&self) -> CommandSender {
+        self.commands.clone()
+    }
+
+    pub fn error(&self) -> Option<&ProtocolError> {
+        self.error.as_ref()
+    }
+
+    pub fn connection(&self) -> &ConnectionState {
+        &self.connection
+    }
+
+    // The app we're connected to, as of the last time we connected
+    pub fn app_info(&self) -> Option<&AppInfo> {
+        self.app_info.as_ref()
+    }
+
+    fn add_event(&mut self, event: ClientEvent) -> Result<(), ansi_to_tui::Error> {
+        let entry = match event {
+            ClientEvent::Record(record) => LogEntry::Record(record),
+            ClientEvent::Recorded(record) => LogEntry::Recorded(record),
+            ClientEvent::Dropped(count) => LogEntry::Dropped(count),
+            ClientEvent::Connection(state) => {
+                self.connection = state;
+                return Ok(());
+            }
+            ClientEvent::AppInfo(info) => {
+                self.app_info = Some(info);
+                return Ok(());
             }
+        };
+        if is_visible(&self.filter, &entry) {
+            self.logs
+                .add_item(record_item::to_list_item(&entry, self.hex_dump)?);
+        }
+        if self.records.len() >= self.max_logs {
+            self.records.pop_front();
+        }
+        self.records.push_back(entry);
+        Ok(())
+    }
+
+    pub fn filter(&self) -> &LogFilter {
+        &self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: LogFilter) -> Result<(), ansi_to_tui::Error> {
+        self.filter = filter;
+        self.rebuild()
+    }
+
+    pub fn hex_dump(&self) -> bool {
+        self.hex_dump
+    }
+
+    pub fn set_hex_dump(&mut self, hex_dump: bool) -> Result<(), ansi_to_tui::Error> {
+        self.hex_dump = hex_dump;
+        self.rebuild()
+    }
+
+    fn rebuild(&mut self) -> Result<(), ansi_to_tui::Error> {
+        self.logs.clear();
+        for entry in self.records.iter().filter(|e| is_visible(&self.filter, e)) {
+            self.logs
+                .add_item(record_item::to_list_item(entry, self.hex_dump)?);
         }
         Ok(())
     }
@@ -114,7 +250,28 @@ impl LogView<'_> {
         self.logs.previous();
     }
 
+    pub fn selected(&self) -> Option<&LogEntry> {
+        let from_end = self.logs.selected_from_end()?;
+        self.records
+            .iter()
+            .rev()
+            .filter(|entry| is_visible(&self.filter, entry))
+            .nth(from_end)
+    }
+
     pub fn render(&mut self, frame: &mut Frame, area: Rect) {
-        self.logs.render(frame, area)
+        let title = match &self.error {
+            Some(e) => format!("Logs ({e})"),
+            None => format!("Logs ({})", self.connection),
+        };
+        self.logs.render(frame, area, title)
+    }
+}
+
+// Gaps are always shown so it's clear that something is missing
+fn is_visible(filter: &LogFilter, entry: &LogEntry) -> bool {
+    match entry {
+        LogEntry::Record(record) | LogEntry::Recorded(record) => filter.matches(record),
+        LogEntry::Dropped(_) => true,
     }
 }
@@ -0,0 +1,48 @@
+use tilia::record::{Level, Record};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LogFilter {
+    pub min_level: Option<Level>,
+    pub target: Option<String>,
+    pub fields: Vec<(String, String)>,
+}
+
+impl LogFilter {
+    pub fn with_min_level(self, min_level: Option<Level>) -> Self {
+        Self { min_level, ..self }
+    }
+
+    pub fn with_target(self, target: impl Into<String>) -> Self {
+        Self {
+            target: Some(target.into()),
+            ..self
+        }
+    }
+
+    pub fn with_field(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.fields.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        // Text records have no structured metadata, so they always match
+        let Record::Event(event) = record else {
+            return true;
+        };
+        if let Some(min_level) = self.min_level {
+            if event.level < min_level {
+                return false;
+            }
+        }
+        if let Some(target) = &self.target {
+            if !event.target.starts_with(target.as_str()) {
+                return false;
+            }
+        }
+        self.fields.iter().all(|(name, value)| {
+            event
+                .field(name)
+                .is_some_and(|field| field.to_string() == *value)
+        })
+    }
+}
@@ -0,0 +1,196 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ansi_to_tui::IntoText;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::ListItem;
+use tilia::record::{Event, Level, Record};
+
+use crate::LogEntry;
+
+// Longer binary payloads are cut off in the hex dump
+const MAX_HEX_DUMP_BYTES: usize = 1024;
+const HEX_DUMP_WIDTH: usize = 16;
+
+pub(crate) fn to_list_item<'a>(
+    entry: &LogEntry,
+    hex_dump: bool,
+) -> Result<ListItem<'a>, ansi_to_tui::Error> {
+    match entry {
+        LogEntry::Record(record) => Ok(ListItem::new(record_text(record, hex_dump)?)),
+        LogEntry::Recorded(record) => {
+            Ok(ListItem::new(mark_recorded(record_text(record, hex_dump)?)))
+        }
+        LogEntry::Dropped(count) => Ok(ListItem::new(dropped_line(*count))),
+    }
+}
+
+fn record_text<'a>(record: &Record, hex_dump: bool) -> Result<Text<'a>, ansi_to_tui::Error> {
+    match record {
+        Record::Text(text) => String::from_utf8_lossy(text).as_bytes().into_text(),
+        Record::Binary(payload) => binary_text(payload, hex_dump),
+        Record::Event(event) => Ok(Text::from(event_line(event))),
+    }
+}
+
+// Fetched from the flight recorder, marked so it isn't mistaken for live output
+fn mark_recorded(mut text: Text<'_>) -> Text<'_> {
+    if let Some(line) = text.lines.first_mut() {
+        let marker = Style::default()
+            .fg(Color::Magenta)
+            .add_modifier(Modifier::BOLD);
+        line.spans.insert(0, Span::styled("recorded ", marker));
+    }
+    text
+}
+
+fn binary_text<'a>(payload: &[u8], hex_dump: bool) -> Result<Text<'a>, ansi_to_tui::Error> {
+    if hex_dump {
+        return Ok(hex_dump_text(payload));
+    }
+    // Keep whatever is readable, and make it obvious where bytes were replaced
+    let text = String::from_utf8_lossy(payload).as_bytes().into_text()?;
+    Ok(Text::from(
+        text.lines
+            .into_iter()
+            .map(mark_unprintable)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn mark_unprintable(line: Line<'_>) -> Line<'_> {
+    let marker = Style::default()
+        .fg(Color::Red)
+        .add_modifier(Modifier::REVERSED);
+    let mut spans = Vec::new();
+    for span in line.spans {
+        let mut plain = String::new();
+        for c in span.content.chars() {
+            let replacement = match c {
+                char::REPLACEMENT_CHARACTER => Some(c),
+                '\t' => None,
+                // Shown as the matching control picture, like ␀ for NUL
+                '\0'..='\x1f' => char::from_u32(0x2400 + c as u32),
+                '\x7f' => Some('␡'),
+                _ => None,
+            };
+            match replacement {
+                Some(replacement) => {
+                    if !plain.is_empty() {
+                        spans.push(Span::styled(std::mem::take(&mut plain), span.style));
+                    }
+                    spans.push(Span::styled(
+                        replacement.to_string(),
+                        span.style.patch(marker),
+                    ));
+                }
+                None => plain.push(c),
+            }
+        }
+        if !plain.is_empty() {
+            spans.push(Span::styled(plain, span.style));
+        }
+    }
+    Line::from(spans)
+}
+
+fn hex_dump_text<'a>(payload: &[u8]) -> Text<'a> {
+    let dim = Style::default().add_modifier(Modifier::DIM);
+    let shown = &payload[..payload.len().min(MAX_HEX_DUMP_BYTES)];
+    let mut lines: Vec<_> = shown
+        .chunks(HEX_DUMP_WIDTH)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<_> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            Line::from(vec![
+                Span::styled(format!("{:08x}  ", i * HEX_DUMP_WIDTH), dim),
+                Span::raw(format!(
+                    "{:<width$}",
+                    hex.join(" "),
+                    width = HEX_DUMP_WIDTH * 3 - 1
+                )),
+                Span::styled(format!("  |{ascii}|"), dim),
+            ])
+        })
+        .collect();
+    if payload.len() > shown.len() {
+        lines.push(Line::styled(
+            format!("··· {} more bytes ···", payload.len() - shown.len()),
+            dim,
+        ));
+    }
+    Text::from(lines)
+}
+
+fn dropped_line<'a>(count: u64) -> Line<'a> {
+    let noun = if count == 1 { "event" } else { "events" };
+    Line::styled(
+        format!("··· {count} {noun} dropped ···"),
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::DIM),
+    )
+}
+
+fn event_line<'a>(event: &Event) -> Line<'a> {
+    let dim = Style::default().add_modifier(Modifier::DIM);
+    let mut spans = vec![
+        Span::styled(format_timestamp(event.timestamp), dim),
+        Span::raw(" "),
+        Span::styled(format!("{:>5}", event.level), level_style(event.level)),
+        Span::raw(" "),
+    ];
+    for span in &event.spans {
+        spans.push(Span::styled(
+            span.to_string(),
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        spans.push(Span::styled(":", dim));
+    }
+    if !event.spans.is_empty() {
+        spans.push(Span::raw(" "));
+    }
+    spans.push(Span::styled(format!("{}:", event.target), dim));
+
+    if let Some(message) = event.message() {
+        spans.push(Span::raw(format!(" {message}")));
+    }
+    let italic = Style::default().add_modifier(Modifier::ITALIC);
+    for field in event.other_fields() {
+        spans.push(Span::styled(format!(" {field}"), italic));
+    }
+    Line::from(spans)
+}
+
+fn level_style(level: Level) -> Style {
+    let color = match level {
+        Level::Trace => Color::Magenta,
+        Level::Debug => Color::Blue,
+        Level::Info => Color::Green,
+        Level::Warn => Color::Yellow,
+        Level::Error => Color::Red,
+    };
+    Style::default().fg(color)
+}
+
+fn format_timestamp(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
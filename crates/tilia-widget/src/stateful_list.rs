@@ -38,6 +38,11 @@ impl<'a> StatefulList<'a> {
         }
     }
 
+    pub(crate) fn clear(&mut self) {
+        self.items.clear();
+        self.state.select(None);
+    }
+
     pub(crate) fn next(&mut self) {
         if let Some(selected) = self.state.selected() {
             if !self.items.is_empty() && selected < self.items.len() - 1 {
@@ -54,13 +59,20 @@ impl<'a> StatefulList<'a> {
         }
     }
 
-    pub(crate) fn render(&mut self, frame: &mut Frame, area: Rect) {
+    // Counted from the newest item, since that's what stays stable as old items are dropped
+    pub(crate) fn selected_from_end(&self) -> Option<usize> {
+        self.state
+            .selected()
+            .and_then(|selected| self.items.len().checked_sub(selected + 1))
+    }
+
+    pub(crate) fn render(&mut self, frame: &mut Frame, area: Rect, title: String) {
         let logs_list = List::new(self.items.clone())
             .block(
                 Block::default()
                     .borders(Borders::all())
                     .border_type(BorderType::Rounded)
-                    .title("Logs"),
+                    .title(title),
             )
             .highlight_style(
                 Style::default()
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use blake2::digest::{Digest, KeyInit, Mac};
+use blake2::{Blake2b512, Blake2bMac512};
+
+const CHALLENGE_LEN: usize = 32;
+
+// Shared between a server and the clients allowed to connect to it. It never goes over the
+// connection, clients prove they know it by signing a random challenge instead.
+// No `PartialEq`, comparing secrets with `==` wouldn't be constant time
+#[derive(Clone)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    // An empty secret would let anyone in while looking like it doesn't
+    pub fn new(secret: impl Into<Vec<u8>>) -> Result<Self, EmptySecret> {
+        let secret = secret.into();
+        if secret.is_empty() {
+            return Err(EmptySecret);
+        }
+        Ok(Self(secret))
+    }
+
+    pub(crate) fn respond(&self, challenge: &[u8]) -> Vec<u8> {
+        self.mac(challenge).finalize().into_bytes().to_vec()
+    }
+
+    // Compared in constant time so the response can't be guessed a byte at a time
+    pub(crate) fn verify(&self, challenge: &[u8], response: &[u8]) -> bool {
+        self.mac(challenge).verify_slice(response).is_ok()
+    }
+
+    fn mac(&self, challenge: &[u8]) -> Blake2bMac512 {
+        // Keys can't be longer than the hash, so the secret is hashed down to size first
+        let key = Blake2b512::digest(&self.0);
+        let mut mac = <Blake2bMac512 as KeyInit>::new(&key);
+        mac.update(challenge);
+        mac
+    }
+}
+
+// Keeps the secret out of logs
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmptySecret;
+
+impl fmt::Display for EmptySecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "secret must not be empty")
+    }
+}
+
+impl std::error::Error for EmptySecret {}
+
+pub(crate) fn challenge() -> Vec<u8> {
+    rand::random::<[u8; CHALLENGE_LEN]>().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_empty_secrets() {
+        assert!(matches!(Secret::new(""), Err(EmptySecret)));
+    }
+
+    #[test]
+    fn verifies_responses_signed_with_the_same_secret() {
+        let challenge = challenge();
+        let response = Secret::new("hunter2").unwrap().respond(&challenge);
+        assert!(
+            Secret::new("hunter2")
+                .unwrap()
+                .verify(&challenge, &response)
+        );
+        assert!(
+            !Secret::new("hunter3")
+                .unwrap()
+                .verify(&challenge, &response)
+        );
+    }
+}
@@ -1,43 +1,457 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::io;
 use std::time::Duration;
+use std::{fmt, io};
 
 use background_service::error::BoxedError;
-use bytes::BytesMut;
-use futures::{Future, Stream, StreamExt};
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
 
-pub async fn run_client<F, S, E, Fut>(make_transport: F, tx: tokio::sync::mpsc::Sender<String>)
+use crate::auth::Secret;
+use crate::protocol::{
+    AppInfo, Command, HANDSHAKE_TIMEOUT, Hello, Message, ProtocolError, Reply, idle, peer_name,
+};
+use crate::record::Record;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// Lets the server drop the connection if this client disappears without closing it
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+type PendingCommand = (Command, oneshot::Sender<Reply>);
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientSettings {
+    filter: Option<String>,
+    backoff: Backoff,
+    idle_timeout: Option<Duration>,
+    secret: Option<Secret>,
+}
+
+impl ClientSettings {
+    pub fn with_filter(self, filter: impl Into<String>) -> Self {
+        Self {
+            filter: Some(filter.into()),
+            ..self
+        }
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+
+    // Used to answer the server's challenge if it requires a secret
+    pub fn with_secret(self, secret: Secret) -> Self {
+        Self {
+            secret: Some(secret),
+            ..self
+        }
+    }
+
+    // Reconnect if a server that sends heartbeats goes quiet for this long, three heartbeat
+    // intervals by default
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout: Some(idle_timeout),
+            ..self
+        }
+    }
+}
+
+// How long to wait between connection attempts, doubling after each failure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn with_initial(self, initial: Duration) -> Self {
+        Self { initial, ..self }
+    }
+
+    pub fn with_max(self, max: Duration) -> Self {
+        Self { max, ..self }
+    }
+
+    // Randomly shortens or lengthens each delay by up to this fraction, so clients that lost
+    // their connection at the same time don't all retry together
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        let jitter = if jitter.is_nan() {
+            0.0
+        } else {
+            jitter.clamp(0.0, 1.0)
+        };
+        Self { jitter, ..self }
+    }
+
+    fn first(&self) -> Duration {
+        self.initial.min(self.max)
+    }
+
+    fn next(&self, delay: Duration) -> Duration {
+        delay.saturating_mul(2).min(self.max)
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        let random = rand::random::<f64>();
+        // Computed in floating point, scaling the `Duration` itself panics on overflow
+        let secs = delay.as_secs_f64() * (1.0 + self.jitter * (random * 2.0 - 1.0));
+        Duration::try_from_secs_f64(secs.min(self.max.as_secs_f64()))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected(String),
+    // Waiting this long before the next attempt
+    Retrying(Duration),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Connected => write!(f, "connected"),
+            Self::Disconnected(reason) => write!(f, "disconnected: {reason}"),
+            Self::Retrying(delay) => write!(f, "retrying in {:.1}s", delay.as_secs_f64()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    Record(Record),
+    // Fetched from the flight recorder with `Command::Recorded`, not part of the live output
+    Recorded(Record),
+    // The server skipped this many events because the client fell behind
+    Dropped(u64),
+    Connection(ConnectionState),
+    // Sent by the server each time the client connects
+    AppInfo(AppInfo),
+}
+
+// Where to continue from after reconnecting to the same server
+#[derive(Clone, Copy, Debug)]
+struct Position {
+    stream_id: u64,
+    next_seq: u64,
+}
+
+pub fn command_channel(buffer: usize) -> (CommandSender, CommandReceiver) {
+    let (tx, rx) = mpsc::channel(buffer);
+    (CommandSender { tx }, CommandReceiver { rx })
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandSender {
+    tx: mpsc::Sender<PendingCommand>,
+}
+
+impl CommandSender {
+    pub async fn send(&self, command: Command) -> Result<Reply, CommandError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .send((command, reply_tx))
+            .await
+            .map_err(|_| CommandError::Closed)?;
+        tokio::time::timeout(REPLY_TIMEOUT, reply_rx)
+            .await
+            .map_err(|_| CommandError::Timeout)?
+            .map_err(|_| CommandError::Disconnected)
+    }
+}
+
+pub struct CommandReceiver {
+    rx: mpsc::Receiver<PendingCommand>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Closed,
+    Disconnected,
+    Timeout,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "client is no longer running"),
+            Self::Disconnected => write!(f, "connection closed before a reply was received"),
+            Self::Timeout => write!(f, "timed out waiting for a reply"),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+pub async fn run_client<F, S, E, Fut>(
+    make_transport: F,
+    settings: ClientSettings,
+    tx: mpsc::Sender<ClientEvent>,
+    mut commands: CommandReceiver,
+) -> Result<(), ProtocolError>
 where
     F: Fn() -> Fut + Clone + Send + Sync,
     Fut: Future<Output = Result<S, BoxedError>> + Send,
-    E: Error + Send + Sync,
-    S: Stream<Item = Result<BytesMut, E>> + Send + Unpin + 'static,
+    E: Error + Send + Sync + 'static,
+    S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Send + Unpin + 'static,
 {
-    let make_client = || async {
+    let make_transport = &make_transport;
+    let set_state = |state| tx.send(ClientEvent::Connection(state));
+    let backoff = settings.backoff;
+    let secret = settings.secret.as_ref();
+    let make_client = |filter: Option<String>, position: Option<Position>| async move {
+        let hello = Hello::current()
+            .with_filter(filter)
+            .with_heartbeat_interval(Some(HEARTBEAT_INTERVAL))
+            .with_peer(Some(peer_name()));
+        let mut delay = backoff.first();
         loop {
-            if let Ok(client) = make_transport().await {
-                break client;
-            } else {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-            }
+            let _ = set_state(ConnectionState::Connecting).await;
+            let reason = match make_transport().await {
+                Ok(mut client) => {
+                    match handshake(&mut client, hello.clone(), position, secret).await {
+                        Ok(remote) => {
+                            let _ = set_state(ConnectionState::Connected).await;
+                            break Ok((client, remote));
+                        }
+                        Err(e) => match e.downcast::<ProtocolError>() {
+                            Ok(e) if e.is_fatal() => break Err(*e),
+                            Ok(e) => e.to_string(),
+                            Err(e) => e.to_string(),
+                        },
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+            let _ = set_state(ConnectionState::Disconnected(reason)).await;
+            let wait = backoff.jittered(delay);
+            let _ = set_state(ConnectionState::Retrying(wait)).await;
+            tokio::time::sleep(wait).await;
+            delay = backoff.next(delay);
         }
     };
 
-    let mut client = make_client().await;
+    // Keep track of the active filter so it can be restored after reconnecting
+    let mut filter = settings.filter;
+    let (mut client, mut remote) = make_client(filter.clone(), None).await?;
+    // Only servers that send heartbeats can be expected to send something regularly
+    let idle_timeout = |remote: &Hello| {
+        remote
+            .idle_timeout()
+            .map(|idle_timeout| settings.idle_timeout.unwrap_or(idle_timeout))
+    };
+    let mut last_received = Instant::now();
+    // Sequence number of the next record, if it's part of the live stream
+    let mut next_seq: Option<u64> = None;
+    // The last position in the live stream, kept while receiving records from outside of it
+    let mut resume_seq = None;
+    let mut pending = HashMap::<u64, PendingCommand>::new();
+    let mut next_id = 0;
+    let mut heartbeat =
+        tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let send = async {
-            let log_bytes = client
-                .next()
-                .await
-                .ok_or(io::Error::new(io::ErrorKind::NotConnected, "end of stream"))??;
-            if let Ok(log_str) = String::from_utf8(log_bytes.to_vec()) {
-                let _ = tx.send(log_str).await;
+        let res = async {
+            tokio::select! {
+                bytes = client.next() => {
+                    last_received = Instant::now();
+                    let bytes = bytes
+                        .ok_or(io::Error::new(io::ErrorKind::NotConnected, "end of stream"))??;
+                    let message = match Message::decode(&bytes) {
+                        Ok(message) => message,
+                        // Show the raw bytes rather than losing the record, it still takes up a
+                        // sequence number
+                        Err(_) if Message::is_record(&bytes) => {
+                            Message::Record(Record::Binary(bytes.to_vec()))
+                        }
+                        Err(_) => return Ok(()),
+                    };
+                    match message {
+                        Message::Record(record) => {
+                            if let Some(seq) = next_seq {
+                                // Overflows only after a bogus sequence number from the server,
+                                // which leaves nowhere to resume from
+                                next_seq = seq.checked_add(1);
+                                // Never move backwards, or a reconnect would repeat entries
+                                resume_seq = next_seq.and(resume_seq.max(next_seq));
+                            }
+                            let _ = tx.send(ClientEvent::Record(record)).await;
+                        }
+                        Message::Sequence { next } => {
+                            next_seq = next;
+                            resume_seq = resume_seq.max(next);
+                        }
+                        Message::Recorded(record) => {
+                            let _ = tx.send(ClientEvent::Recorded(record)).await;
+                        }
+                        Message::Dropped { count } => {
+                            let _ = tx.send(ClientEvent::Dropped(count)).await;
+                        }
+                        Message::AppInfo(info) => {
+                            let _ = tx.send(ClientEvent::AppInfo(info)).await;
+                        }
+                        Message::Reply { id, reply } => {
+                            if let Some((command, reply_tx)) = pending.remove(&id) {
+                                if let (Command::SetFilter(directives), Reply::Ok) =
+                                    (command, &reply)
+                                {
+                                    filter = directives;
+                                }
+                                let _ = reply_tx.send(reply);
+                            }
+                        }
+                        Message::Goodbye { reason } => {
+                            return Err(
+                                io::Error::new(io::ErrorKind::ConnectionAborted, reason).into()
+                            );
+                        }
+                        Message::Hello(_)
+                        | Message::Command { .. }
+                        | Message::Heartbeat
+                        | Message::Authenticated
+                        | Message::Unknown(_) => {}
+                    }
+                }
+                _ = idle(last_received, idle_timeout(&remote)) => {
+                    return Err(
+                        io::Error::new(io::ErrorKind::TimedOut, "server stopped responding").into()
+                    );
+                }
+                Some((command, reply_tx)) = commands.rx.recv() => {
+                    next_id += 1;
+                    let message = Message::Command { id: next_id, command: command.clone() };
+                    pending.insert(next_id, (command, reply_tx));
+                    client.send(Bytes::from(message.encode())).await?;
+                }
+                _ = heartbeat.tick() => {
+                    client.send(Bytes::from(Message::Heartbeat.encode())).await?;
+                }
             }
             Ok::<_, BoxedError>(())
         };
 
-        if send.await.is_err() {
-            client = make_client().await;
+        if let Err(e) = res.await {
+            let _ = set_state(ConnectionState::Disconnected(e.to_string())).await;
+            // Any outstanding replies were lost with the connection
+            pending.clear();
+            let position = remote
+                .stream_id
+                .zip(resume_seq)
+                .map(|(stream_id, next_seq)| Position {
+                    stream_id,
+                    next_seq,
+                });
+            let previous_stream = remote.stream_id;
+            (client, remote) = make_client(filter.clone(), position).await?;
+            last_received = Instant::now();
+            next_seq = None;
+            if remote.stream_id != previous_stream {
+                resume_seq = None;
+            }
         }
     }
 }
+
+// Returns the server's hello
+async fn handshake<S, E>(
+    client: &mut S,
+    local: Hello,
+    position: Option<Position>,
+    secret: Option<&Secret>,
+) -> Result<Hello, BoxedError>
+where
+    E: Error + Send + Sync + 'static,
+    S: Stream<Item = Result<BytesMut, E>> + Sink<Bytes, Error = E> + Unpin,
+{
+    match receive(client).await? {
+        Message::Hello(remote) => {
+            // Sequence numbers from a different stream, like after the app restarted, mean nothing
+            let resume_from = position
+                .filter(|position| remote.stream_id == Some(position.stream_id))
+                .map(|position| position.next_seq);
+            let response = secret
+                .zip(remote.challenge.as_deref())
+                .map(|(secret, challenge)| secret.respond(challenge));
+            let local = local.with_resume_from(resume_from).with_response(response);
+            let version = local.negotiate(&remote);
+            client
+                .send(Bytes::from(Message::Hello(local).encode()))
+                .await?;
+            version?;
+            if remote.challenge.is_none() {
+                return Ok(remote);
+            }
+            // Wait for the verdict, retrying with the same secret would only fail again
+            match receive(client).await? {
+                Message::Authenticated => Ok(remote),
+                Message::Goodbye { reason } => Err(ProtocolError::Rejected(reason).into()),
+                _ => Err(ProtocolError::UnexpectedMessage("authenticated").into()),
+            }
+        }
+        Message::Goodbye { reason } => Err(ProtocolError::Rejected(reason).into()),
+        _ => Err(ProtocolError::UnexpectedMessage("hello").into()),
+    }
+}
+
+async fn receive<S, E>(client: &mut S) -> Result<Message, BoxedError>
+where
+    E: Error + Send + Sync + 'static,
+    S: Stream<Item = Result<BytesMut, E>> + Unpin,
+{
+    let bytes = tokio::time::timeout(HANDSHAKE_TIMEOUT, client.next())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out during the handshake"))?
+        .ok_or(io::Error::new(io::ErrorKind::NotConnected, "end of stream"))??;
+    Ok(Message::decode(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_never_exceeds_the_max() {
+        let backoff = Backoff::default()
+            .with_initial(Duration::from_secs(60))
+            .with_max(Duration::from_secs(10))
+            .with_jitter(1.0);
+        let mut delay = backoff.first();
+        for _ in 0..100 {
+            assert!(backoff.jittered(delay) <= Duration::from_secs(10));
+            delay = backoff.next(delay);
+        }
+        assert_eq!(delay, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn backoff_handles_an_unbounded_max_and_nan_jitter() {
+        let backoff = Backoff::default().with_max(Duration::MAX).with_jitter(1.0);
+        let mut delay = backoff.first();
+        for _ in 0..100 {
+            backoff.jittered(delay);
+            delay = backoff.next(delay);
+        }
+        assert_eq!(delay, Duration::MAX);
+
+        let backoff = Backoff::default().with_jitter(f64::NAN);
+        assert_eq!(backoff.jittered(backoff.first()), backoff.first());
+    }
+}
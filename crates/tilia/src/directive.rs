@@ -0,0 +1,193 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::record::Level;
+
+// A subset of `tracing_subscriber::EnvFilter` syntax that can be evaluated against records after
+// they've left the process, e.g. `my_crate::db=debug,warn`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Directives {
+    // Sorted from most to least specific
+    directives: Vec<Directive>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Directive {
+    target: Option<String>,
+    // `None` turns the target off
+    level: Option<Level>,
+}
+
+impl Directives {
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        self.directives
+            .iter()
+            .find(|directive| {
+                directive
+                    .target
+                    .as_ref()
+                    .is_none_or(|prefix| target.starts_with(prefix.as_str()))
+            })
+            .and_then(|directive| directive.level)
+            .is_some_and(|min_level| level >= min_level)
+    }
+
+    // No directives at all, e.g. from an empty string, which callers treat as no filter
+    pub fn is_empty(&self) -> bool {
+        self.directives.is_empty()
+    }
+
+    // The most verbose level any directive can enable
+    pub fn max_verbosity(&self) -> Option<Level> {
+        self.directives
+            .iter()
+            .filter_map(|directive| directive.level)
+            .min()
+    }
+}
+
+impl FromStr for Directives {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut directives = s
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+            .map(parse_directive)
+            .collect::<Result<Vec<_>, _>>()?;
+        // Like `EnvFilter`, a later directive for the same target overrides an earlier one
+        directives.reverse();
+        directives.sort_by_key(|directive| {
+            std::cmp::Reverse(directive.target.as_ref().map(|target| target.len()))
+        });
+        Ok(Self { directives })
+    }
+}
+
+impl fmt::Display for Directives {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directives: Vec<_> = self
+            .directives
+            .iter()
+            .map(|directive| {
+                let level = directive
+                    .level
+                    .map(|level| level.as_str().to_lowercase())
+                    .unwrap_or_else(|| "off".to_owned());
+                match &directive.target {
+                    Some(target) => format!("{target}={level}"),
+                    None => level,
+                }
+            })
+            .collect();
+        f.write_str(&directives.join(","))
+    }
+}
+
+fn parse_directive(directive: &str) -> Result<Directive, ParseError> {
+    match directive.split_once('=') {
+        Some((target, level)) => Ok(Directive {
+            target: Some(parse_target(target, directive)?),
+            level: parse_level(level.trim())
+                .ok_or_else(|| ParseError::new(directive, "invalid level"))?,
+        }),
+        None => match parse_level(directive) {
+            Some(level) => Ok(Directive {
+                target: None,
+                level,
+            }),
+            // A bare target enables everything for that target
+            None => Ok(Directive {
+                target: Some(parse_target(directive, directive)?),
+                level: Some(Level::Trace),
+            }),
+        },
+    }
+}
+
+fn parse_target(target: &str, directive: &str) -> Result<String, ParseError> {
+    let target = target.trim();
+    if target.is_empty() || target.contains(['[', ']', '{', '}']) {
+        return Err(ParseError::new(directive, "invalid target"));
+    }
+    Ok(target.to_owned())
+}
+
+fn parse_level(level: &str) -> Option<Option<Level>> {
+    if level.eq_ignore_ascii_case("off") {
+        Some(None)
+    } else {
+        level.parse().ok().map(Some)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    directive: String,
+    reason: &'static str,
+}
+
+impl ParseError {
+    fn new(directive: &str, reason: &'static str) -> Self {
+        Self {
+            directive: directive.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in directive '{}'", self.reason, self.directive)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_the_most_specific_target() {
+        let directives: Directives = "warn,my_app::db=debug,my_app::db::pool=off"
+            .parse()
+            .unwrap();
+
+        assert!(directives.enabled("my_app::db::query", Level::Debug));
+        assert!(!directives.enabled("my_app::db::pool", Level::Error));
+        assert!(!directives.enabled("my_app::http", Level::Info));
+        assert!(directives.enabled("my_app::http", Level::Warn));
+        assert_eq!(directives.max_verbosity(), Some(Level::Debug));
+    }
+
+    #[test]
+    fn later_directives_win() {
+        let directives: Directives = "my_app=info,my_app=warn".parse().unwrap();
+
+        assert!(!directives.enabled("my_app", Level::Info));
+        assert!(directives.enabled("my_app", Level::Warn));
+    }
+
+    #[test]
+    fn bare_targets_enable_everything() {
+        let directives: Directives = "my_app".parse().unwrap();
+
+        assert!(directives.enabled("my_app::db", Level::Trace));
+        assert!(!directives.enabled("other", Level::Error));
+    }
+
+    #[test]
+    fn empty_strings_have_no_directives() {
+        assert!("".parse::<Directives>().unwrap().is_empty());
+        assert!(" , ,".parse::<Directives>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_directives() {
+        assert!("my_app=loud".parse::<Directives>().is_err());
+        assert!("=info".parse::<Directives>().is_err());
+        assert!("my_app[span]=info".parse::<Directives>().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use background_service::ServiceContext;
+use background_service::error::BoxedError;
+use bytes::Bytes;
+
+use crate::history::{self, Entry};
+use crate::protocol::Message;
+use crate::record::Record;
+use crate::{DIAGNOSTICS_TARGET, spill};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    // One line per record, for reading without the console
+    #[default]
+    Text,
+    // The spill file format, which the console can open
+    Replay,
+}
+
+// Writes the history to a file when the app panics, or on demand
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dump {
+    path: PathBuf,
+    format: DumpFormat,
+    on_panic: bool,
+    on_signal: bool,
+}
+
+impl Dump {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            format: DumpFormat::default(),
+            on_panic: true,
+            on_signal: false,
+        }
+    }
+
+    pub fn with_format(self, format: DumpFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    pub fn with_on_panic(self, on_panic: bool) -> Self {
+        Self { on_panic, ..self }
+    }
+
+    // Also dump whenever the process receives SIGUSR1, once the server has started. Only
+    // supported on unix.
+    pub fn with_on_signal(self, on_signal: bool) -> Self {
+        Self { on_signal, ..self }
+    }
+
+    pub(crate) fn on_panic(&self) -> bool {
+        self.on_panic
+    }
+
+    pub(crate) fn on_signal(&self) -> bool {
+        self.on_signal
+    }
+
+    pub(crate) fn write(&self, entries: &[Arc<Entry>], note: Option<String>) -> io::Result<()> {
+        let note = note.map(|note| Message::Record(Record::Text(note.into_bytes())).encode());
+        let frames = entries
+            .iter()
+            .map(|entry| entry.frame.clone())
+            .chain(note.map(Bytes::from));
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        for frame in frames {
+            match self.format {
+                DumpFormat::Text => write_text(&mut out, &frame)?,
+                DumpFormat::Replay => spill::write_frame(&mut out, &frame)?,
+            }
+        }
+        out.flush()
+    }
+}
+
+// Chains onto the existing hook so the default panic output is kept. Only the first panic is
+// dumped, a later one that's caught with `catch_unwind` would otherwise overwrite it. The hook
+// can't be removed again, so it only holds on to the history until the writer is dropped.
+pub(crate) fn install_panic_hook(dump: Dump, history: &history::Sender) {
+    let history = history.downgrade();
+    let prev = std::panic::take_hook();
+    let dumped = AtomicBool::new(false);
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(history) = history.upgrade()
+            && !dumped.swap(true, Ordering::AcqRel)
+        {
+            let _ = dump.write(&history.snapshot(), Some(info.to_string()));
+        }
+        prev(info);
+    }));
+}
+
+#[cfg(unix)]
+pub(crate) async fn dump_on_signal(
+    dump: Dump,
+    history: history::Sender,
+    context: ServiceContext,
+) -> Result<(), BoxedError> {
+    use tokio::signal::unix::{SignalKind, signal};
+    use tokio_util::future::FutureExt;
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    while let Some(Some(())) = signals
+        .recv()
+        .with_cancellation_token(context.cancellation_token())
+        .await
+    {
+        let writer = dump.clone();
+        let entries = history.snapshot();
+        let res = tokio::task::spawn_blocking(move || writer.write(&entries, None))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        // Keep listening, the next attempt may well succeed
+        if let Err(e) = res {
+            tracing::error!(
+                target: DIAGNOSTICS_TARGET,
+                "failed to dump history to {}: {e}",
+                dump.path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn dump_on_signal(
+    _dump: Dump,
+    _history: history::Sender,
+    _context: ServiceContext,
+) -> Result<(), BoxedError> {
+    Ok(())
+}
+
+fn write_text(out: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    match Message::decode(frame) {
+        Ok(Message::Record(Record::Event(event))) => {
+            writeln!(out, "{} {event}", format_timestamp(event.timestamp))
+        }
+        Ok(Message::Record(Record::Text(text) | Record::Binary(text))) => {
+            out.write_all(&text)?;
+            if !text.ends_with(b"\n") {
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Seconds since the epoch, since the dump may be read far from where it was written
+fn format_timestamp(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:03}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis()
+    )
+}
@@ -1,15 +1,17 @@
 use std::marker::PhantomData;
-use std::sync::atomic::Ordering;
 
 use tracing::metadata::LevelFilter;
+use tracing::span;
 use tracing::subscriber::Interest;
+use tracing_subscriber::layer::Context;
 
-use crate::state;
+use crate::WriterHandle;
 
 pub struct Filter<F, S>
 where
     F: tracing_subscriber::layer::Filter<S>,
 {
+    handle: WriterHandle,
     inner: F,
     _phantom: PhantomData<S>,
 }
@@ -18,44 +20,79 @@ impl<F, S> Filter<F, S>
 where
     F: tracing_subscriber::layer::Filter<S>,
 {
-    pub fn new(inner: F) -> Self {
+    pub fn new(handle: WriterHandle, inner: F) -> Self {
         Self {
+            handle,
             inner,
             _phantom: PhantomData,
         }
     }
 }
 
-impl<S> Default for Filter<LevelFilter, S> {
-    fn default() -> Self {
-        Self {
-            inner: LevelFilter::TRACE,
-            _phantom: Default::default(),
-        }
-    }
-}
-
 impl<F, S> tracing_subscriber::layer::Filter<S> for Filter<F, S>
 where
     F: tracing_subscriber::layer::Filter<S>,
 {
-    fn enabled(
-        &self,
-        meta: &tracing::Metadata<'_>,
-        cx: &tracing_subscriber::layer::Context<'_, S>,
-    ) -> bool {
-        if state::IS_ENABLED.load(Ordering::SeqCst) {
-            self.inner.enabled(meta, cx)
+    fn enabled(&self, meta: &tracing::Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        let state = &self.handle.state;
+        if state.is_enabled() && state.demand_enabled(meta.level().into()) {
+            state
+                .capture_enabled(meta)
+                .unwrap_or_else(|| self.inner.enabled(meta, cx))
         } else {
             false
         }
     }
 
     fn callsite_enabled(&self, meta: &'static tracing::Metadata<'static>) -> Interest {
-        if state::IS_ENABLED.load(Ordering::SeqCst) {
-            self.inner.callsite_enabled(meta)
+        let state = &self.handle.state;
+        if state.is_enabled() && state.demand_enabled(meta.level().into()) {
+            match state.capture_enabled(meta) {
+                Some(true) => Interest::always(),
+                Some(false) => Interest::never(),
+                None => self.inner.callsite_enabled(meta),
+            }
         } else {
             Interest::never()
         }
     }
+
+    fn event_enabled(&self, event: &tracing::Event<'_>, cx: &Context<'_, S>) -> bool {
+        self.handle
+            .state
+            .capture_enabled(event.metadata())
+            .is_some()
+            || self.inner.event_enabled(event, cx)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        match self.handle.state.capture_max_verbosity() {
+            Some(max_verbosity) => Some(
+                max_verbosity
+                    .map(|level| LevelFilter::from_level(level.into()))
+                    .unwrap_or(LevelFilter::OFF),
+            ),
+            None => self.inner.max_level_hint(),
+        }
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(id, values, ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
 }
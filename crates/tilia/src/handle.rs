@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use background_service::error::BackgroundServiceErrors;
+use tracing::metadata::LevelFilter;
+
+use crate::Filter;
+use crate::state::State;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HistoryUsage {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+// Controls a single writer and everything it started, independently of any other writers
+#[derive(Clone)]
+pub struct WriterHandle {
+    pub(crate) state: Arc<State>,
+}
+
+impl WriterHandle {
+    pub(crate) fn new(state: Arc<State>) -> Self {
+        Self { state }
+    }
+
+    // Starts the server again after it was stopped, returns false if there's no tokio runtime
+    pub fn start(&self) -> bool {
+        self.state.start()
+    }
+
+    pub async fn stop(&self) -> Result<(), BackgroundServiceErrors> {
+        self.state.stop().await
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.is_running()
+    }
+
+    pub fn enable(&self) {
+        self.state.set_enabled(true);
+    }
+
+    pub fn disable(&self) {
+        self.state.set_enabled(false);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.is_enabled()
+    }
+
+    pub fn history_usage(&self) -> HistoryUsage {
+        self.state.history_usage()
+    }
+
+    pub fn filter<S>(&self) -> Filter<LevelFilter, S> {
+        Filter::new(self.clone(), LevelFilter::TRACE)
+    }
+}
@@ -1,59 +1,972 @@
-use std::collections::VecDeque;
-use std::sync::{Arc, RwLock};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
 
-use tokio::sync::broadcast;
+use arc_swap::ArcSwapOption;
+use bytes::Bytes;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::Notify;
+
+use crate::directive::Directives;
+use crate::protocol::Around;
+use crate::record::Level;
+use crate::spill::SpillWriter;
+use crate::{FlightRecorder, HistoryUsage};
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    // Retained entries for anything not covered by a retention tier
+    pub capacity: usize,
+    // Separate capacities for entries at or above each level
+    pub retention: Vec<(Level, usize)>,
+    pub max_bytes: Option<usize>,
+    // Entries older than this aren't replayed to new subscribers
+    pub max_age: Option<Duration>,
+    // Smaller limit for the base tier while nobody is subscribed
+    pub idle_capacity: Option<usize>,
+    // How long to wait for slow receivers to catch up before they start lagging
+    pub block_timeout: Option<Duration>,
+    pub spill: Option<SpillWriter>,
+    pub recorder: Option<FlightRecorder>,
+}
+
+pub fn channel(settings: Settings) -> Sender {
+    let mut retention = settings.retention;
+    retention.sort_by_key(|(level, _)| *level);
+    // The base tier goes first so it's the first to be evicted when over the byte budget
+    let tiers = [(None, settings.capacity)]
+        .into_iter()
+        .chain(
+            retention
+                .into_iter()
+                .map(|(level, capacity)| (Some(level), capacity)),
+        )
+        .map(|(min_level, capacity)| Tier {
+            min_level,
+            ring: Ring::new(capacity),
+        })
+        .collect();
 
-pub fn channel(capacity: usize) -> Sender {
-    let (tx, _) = broadcast::channel(capacity);
     Sender {
-        size: 0,
-        capacity,
-        buf: Arc::new(RwLock::new(VecDeque::with_capacity(capacity))),
-        tx,
+        shared: Arc::new(Shared {
+            tiers,
+            seq: AtomicU64::new(0),
+            claim: Mutex::new(()),
+            stream_id: RandomState::new().build_hasher().finish(),
+            max_bytes: settings.max_bytes,
+            max_age: settings.max_age,
+            idle_capacity: settings.idle_capacity,
+            block_timeout: settings.block_timeout,
+            spill: settings.spill,
+            recorder: settings.recorder.map(|recorder| Recorder {
+                stream_level: recorder.stream_level,
+                ring: Ring::new(recorder.capacity),
+            }),
+            receivers: AtomicUsize::new(0),
+            notify: Notify::new(),
+        }),
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub(crate) seq: u64,
+    pub(crate) meta: Option<EntryMeta>,
+    pub(crate) frame: Bytes,
+    // Position within the tier's ring
+    pos: u64,
+    // Monotonic, for expiry
+    created: Instant,
+    // Wall clock, for finding entries around a point in time a client asks for
+    timestamp: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct EntryMeta {
+    pub(crate) level: Level,
+    pub(crate) target: String,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.frame.len() + self.meta.as_ref().map_or(0, |meta| meta.target.len())
+    }
+
+    // Entries without metadata can't be filtered, so they're always sent
+    pub(crate) fn matches(&self, directives: &Directives) -> bool {
+        self.meta
+            .as_ref()
+            .is_none_or(|meta| directives.enabled(&meta.target, meta.level))
+    }
+}
+
+struct Shared {
+    // Ordered from least to most important
+    tiers: Vec<Tier>,
+    // Orders entries across tiers. With a single tier, its positions are used instead.
+    seq: AtomicU64,
+    // Held just long enough to claim a sequence number and a position in a tier, which is only
+    // needed to merge retention tiers
+    claim: Mutex<()>,
+    // Distinguishes this history from the one in another run of the app
+    stream_id: u64,
+    max_bytes: Option<usize>,
+    max_age: Option<Duration>,
+    idle_capacity: Option<usize>,
+    block_timeout: Option<Duration>,
+    spill: Option<SpillWriter>,
+    recorder: Option<Recorder>,
+    receivers: AtomicUsize,
+    notify: Notify,
+}
+
+impl Shared {
+    fn next_seq(&self) -> u64 {
+        match self.tiers.as_slice() {
+            [tier] => tier.ring.head.load(Ordering::Acquire),
+            _ => self.seq.load(Ordering::Acquire),
+        }
+    }
+
+    fn tier_index(&self, meta: Option<&EntryMeta>) -> usize {
+        let Some(meta) = meta else {
+            return 0;
+        };
+        self.tiers
+            .iter()
+            .rposition(|tier| {
+                tier.min_level
+                    .is_none_or(|min_level| meta.level >= min_level)
+            })
+            .unwrap_or(0)
+    }
+
+    fn bytes(&self) -> usize {
+        self.tiers
+            .iter()
+            .map(|tier| tier.ring.bytes.load(Ordering::Acquire))
+            .sum()
+    }
+
+    // Every tier is checked, since a quiet one would otherwise hold on to its stale entries
+    fn expire(&self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        for tier in &self.tiers {
+            tier.ring.discard_until(tier.ring.first_unexpired(max_age));
+        }
+    }
+
+    // Evicts from the least important tiers first
+    fn enforce_byte_budget(&self, newest_tier: usize, newest_pos: u64) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        while self.bytes() > max_bytes {
+            // The newest entry is always kept, even if it's bigger than the whole budget on its own
+            let evictable = self.tiers.iter().enumerate().find_map(|(i, tier)| {
+                let oldest = tier.ring.oldest();
+                let newest = if i == newest_tier {
+                    newest_pos
+                } else {
+                    tier.ring.head.load(Ordering::Acquire)
+                };
+                (oldest < newest).then_some((tier, oldest))
+            });
+            let Some((tier, oldest)) = evictable else {
+                return;
+            };
+            tier.ring.discard_until(oldest + 1);
+        }
+    }
+}
+
+// Keeps every entry, including the ones below the stream level that receivers never see
+struct Recorder {
+    stream_level: Level,
+    ring: Ring,
+}
+
+struct Tier {
+    // `None` for the base tier
+    min_level: Option<Level>,
+    ring: Ring,
+}
+
+// Fixed slots indexed by position. Writers only claim a position and swap a pointer, so logging
+// never waits on readers, and without retention tiers never takes a lock either.
+struct Ring {
+    slots: Box<[ArcSwapOption<Entry>]>,
+    // Position of the next entry to be written
+    head: AtomicU64,
+    // Entries before this were discarded to stay within the idle capacity or byte budget
+    tail: AtomicU64,
+    // Every entry before this sequence number may have been evicted
+    evicted_seq: AtomicU64,
+    // What the last lap of evicted entries were, so a resuming client can be told about just the
+    // gaps its filter would have shown
+    graveyard: Box<[ArcSwapOption<Evicted>]>,
+    // Usage of the entries currently retained
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    // Read positions, only consulted when blocking on slow receivers
+    cursors: RwLock<Vec<Arc<Cursor>>>,
+    // Lets writers blocked on slow receivers wait for them to move instead of polling
+    waiters: AtomicUsize,
+    progress: Mutex<()>,
+    progressed: Condvar,
+}
+
+struct Evicted {
+    seq: u64,
+    meta: Option<EntryMeta>,
+}
+
+impl Evicted {
+    fn matches(&self, directives: &Directives) -> bool {
+        self.meta
+            .as_ref()
+            .is_none_or(|meta| directives.enabled(&meta.target, meta.level))
+    }
+}
+
+// A receiver's read position in one ring
+struct Cursor {
+    next: AtomicU64,
+    // Paused receivers aren't waited for, or logging would stall for as long as they're paused
+    paused: AtomicBool,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity.max(1))
+                .map(|_| ArcSwapOption::empty())
+                .collect(),
+            head: AtomicU64::new(0),
+            tail: AtomicU64::new(0),
+            evicted_seq: AtomicU64::new(0),
+            graveyard: (0..capacity.max(1))
+                .map(|_| ArcSwapOption::empty())
+                .collect(),
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            cursors: RwLock::new(Vec::new()),
+            waiters: AtomicUsize::new(0),
+            progress: Mutex::new(()),
+            progressed: Condvar::new(),
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    fn slot(&self, pos: u64) -> &ArcSwapOption<Entry> {
+        &self.slots[(pos % self.capacity()) as usize]
+    }
+
+    fn oldest(&self) -> u64 {
+        let head = self.head.load(Ordering::Acquire);
+        head.saturating_sub(self.capacity())
+            .max(self.tail.load(Ordering::Acquire))
+    }
+
+    // Claims the next position and stores the entry there
+    fn push(&self, seq: u64, meta: Option<EntryMeta>, frame: Bytes) {
+        let pos = self.head.fetch_add(1, Ordering::AcqRel);
+        self.write(pos, seq, meta, frame);
+    }
+
+    // Stores an entry at a position that was already claimed
+    fn write(&self, pos: u64, seq: u64, meta: Option<EntryMeta>, frame: Bytes) {
+        self.store(Entry {
+            seq,
+            meta,
+            frame,
+            pos,
+            created: Instant::now(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    fn store(&self, entry: Entry) {
+        let entry = Arc::new(entry);
+        let pos = entry.pos;
+        // Writers a lap apart share a slot and can finish in either order, the position stored
+        // with each entry makes sure the newer one ends up there
+        let prev = self.slot(pos).rcu(|prev| match prev {
+            Some(prev) if prev.pos > pos => Some(prev.clone()),
+            _ => Some(entry.clone()),
+        });
+        match prev {
+            Some(prev) if prev.pos > pos => {
+                self.bury(entry);
+                return;
+            }
+            Some(prev) => {
+                self.bytes.fetch_add(entry.size(), Ordering::AcqRel);
+                self.bytes.fetch_sub(prev.size(), Ordering::AcqRel);
+                self.bury(prev);
+            }
+            None => {
+                self.bytes.fetch_add(entry.size(), Ordering::AcqRel);
+                self.entries.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+        // Discarded while it was being stored
+        if pos < self.tail.load(Ordering::Acquire) {
+            self.discard(pos);
+        }
+    }
+
+    // Drops everything before `tail`, ignoring slots that were already reused for newer entries
+    fn discard_until(&self, tail: u64) {
+        let prev_tail = self.tail.fetch_max(tail, Ordering::AcqRel);
+        let head = self.head.load(Ordering::Acquire);
+        for pos in prev_tail.max(head.saturating_sub(self.capacity()))..tail {
+            self.discard(pos);
+        }
+    }
+
+    fn discard(&self, pos: u64) {
+        let prev = self.slot(pos).rcu(|entry| match entry {
+            Some(entry) if entry.pos == pos => None,
+            entry => entry.clone(),
+        });
+        if let Some(prev) = prev
+            && prev.pos == pos
+        {
+            self.entries.fetch_sub(1, Ordering::AcqRel);
+            self.bytes.fetch_sub(prev.size(), Ordering::AcqRel);
+            self.bury(prev);
+        }
+    }
+
+    fn bury(&self, entry: Arc<Entry>) {
+        self.evicted_seq.fetch_max(entry.seq + 1, Ordering::AcqRel);
+        let pos = entry.pos;
+        let (seq, meta) = match Arc::try_unwrap(entry) {
+            Ok(entry) => (entry.seq, entry.meta),
+            Err(entry) => (entry.seq, entry.meta.clone()),
+        };
+        self.graveyard[(pos % self.capacity()) as usize]
+            .store(Some(Arc::new(Evicted { seq, meta })));
+    }
+
+    // Recently evicted entries with a sequence number in `range`
+    fn buried(&self, range: &std::ops::Range<u64>) -> Vec<Arc<Evicted>> {
+        self.graveyard
+            .iter()
+            .filter_map(|evicted| evicted.load_full())
+            .filter(|evicted| range.contains(&evicted.seq))
+            .collect()
+    }
+
+    fn entries(&self) -> Vec<Arc<Entry>> {
+        let head = self.head.load(Ordering::Acquire);
+        (self.oldest()..head)
+            .filter_map(|pos| self.slot(pos).load_full().filter(|entry| entry.pos == pos))
+            .collect()
+    }
+
+    // Position of the first retained entry at or after `seq`
+    fn position_of(&self, seq: u64) -> u64 {
+        let oldest = self.oldest();
+        let mut pos = self.head.load(Ordering::Acquire);
+        while pos > oldest {
+            match &*self.slot(pos - 1).load() {
+                Some(entry) if entry.pos == pos - 1 && entry.seq < seq => break,
+                // Anything not written yet is newer than `seq`
+                _ => pos -= 1,
+            }
+        }
+        pos
+    }
+
+    // Position of the first entry that's still within the max age
+    fn first_unexpired(&self, max_age: Duration) -> u64 {
+        let head = self.head.load(Ordering::Acquire);
+        let mut pos = self.oldest();
+        while pos < head {
+            match &*self.slot(pos).load() {
+                Some(entry) if entry.pos == pos && entry.created.elapsed() > max_age => pos += 1,
+                _ => break,
+            }
+        }
+        pos
+    }
+
+    // True if the next entry would overwrite one that an active receiver hasn't read yet
+    fn is_full(&self) -> bool {
+        let head = self.head.load(Ordering::Acquire);
+        self.cursors
+            .read()
+            .expect("Lock poisoned")
+            .iter()
+            .filter(|cursor| !cursor.paused.load(Ordering::Acquire))
+            .map(|cursor| cursor.next.load(Ordering::Acquire))
+            .min()
+            .is_some_and(|slowest| head - slowest.min(head) >= self.capacity())
+    }
+
+    fn wait_for_receivers(&self, block_timeout: Duration) {
+        let deadline = Instant::now() + block_timeout;
+        // Announced before checking, so a receiver that moves in between still wakes us up
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let mut progress = self.progress.lock().expect("Lock poisoned");
+        while self.is_full() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            progress = self
+                .progressed
+                .wait_timeout(progress, remaining)
+                .expect("Lock poisoned")
+                .0;
+        }
+        drop(progress);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Called by receivers whenever their cursor moves or stops counting
+    fn notify_progress(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _progress = self.progress.lock().expect("Lock poisoned");
+            self.progressed.notify_all();
+        }
+    }
+}
+
+// Blocking the runtime that serves the receivers would keep them from ever catching up
+fn block_for_receivers(ring: &Ring, block_timeout: Duration) {
+    match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Err(_) => ring.wait_for_receivers(block_timeout),
+        Ok(RuntimeFlavor::MultiThread) => {
+            tokio::task::block_in_place(|| ring.wait_for_receivers(block_timeout));
+        }
+        // A current thread runtime can't run anything else while this thread waits
+        Ok(_) => {}
     }
 }
 
 #[derive(Clone)]
 pub struct Sender {
-    size: usize,
-    capacity: usize,
-    buf: Arc<RwLock<VecDeque<Vec<u8>>>>,
-    tx: broadcast::Sender<Vec<u8>>,
+    shared: Arc<Shared>,
+}
+
+// Doesn't keep the history alive, for things that outlive the writer like a panic hook
+pub struct WeakSender {
+    shared: Weak<Shared>,
+}
+
+impl WeakSender {
+    pub fn upgrade(&self) -> Option<Sender> {
+        self.shared.upgrade().map(|shared| Sender { shared })
+    }
 }
 
 impl Sender {
-    pub fn send(&mut self, value: Vec<u8>) -> Result<usize, broadcast::error::SendError<Vec<u8>>> {
-        let mut buf = self.buf.write().expect("Lock poisoned");
-        if self.size >= self.capacity {
-            buf.pop_front();
+    pub fn downgrade(&self) -> WeakSender {
+        WeakSender {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    pub fn send(&self, meta: Option<EntryMeta>, frame: Bytes) {
+        let shared = &self.shared;
+        // Only streamed entries take up a sequence number, so clients can tell exactly how many
+        // they missed. Detailed entries share the number of the next one.
+        if let Some(recorder) = &shared.recorder
+            && meta
+                .as_ref()
+                .is_some_and(|meta| meta.level < recorder.stream_level)
+        {
+            recorder.ring.push(shared.next_seq(), meta, frame);
+            return;
+        }
+        let tier_index = shared.tier_index(meta.as_ref());
+        let ring = &shared.tiers[tier_index].ring;
+        let has_receivers = shared.receivers.load(Ordering::Acquire) > 0;
+        if has_receivers && let Some(block_timeout) = shared.block_timeout {
+            block_for_receivers(ring, block_timeout);
+        }
+
+        if let Some(spill) = &shared.spill {
+            spill.write(frame.clone());
         }
-        buf.push_back(value.clone());
-        let res = self.tx.send(value);
-        if self.size < self.capacity {
-            self.size += 1;
+        let (seq, pos) = if shared.tiers.len() == 1 {
+            let pos = ring.head.fetch_add(1, Ordering::AcqRel);
+            (pos, pos)
+        } else {
+            // Claimed together, so once an entry is visible every lower sequence number already
+            // has a position in its tier, which is what lets receivers merge the tiers in order
+            let _claim = shared.claim.lock().expect("Lock poisoned");
+            (
+                shared.seq.fetch_add(1, Ordering::AcqRel),
+                ring.head.fetch_add(1, Ordering::AcqRel),
+            )
+        };
+        if let Some(recorder) = &shared.recorder {
+            recorder.ring.push(seq, meta.clone(), frame.clone());
         }
-        res
+        ring.write(pos, seq, meta, frame);
+
+        if !has_receivers
+            && tier_index == 0
+            && let Some(idle_capacity) = shared.idle_capacity
+        {
+            // Release anything that fell out of the idle window
+            ring.discard_until((pos + 1).saturating_sub(idle_capacity as u64));
+        }
+        shared.expire();
+        shared.enforce_byte_budget(tier_index, pos);
+        // Checked again after storing, a receiver registered since the first check would
+        // otherwise miss the wakeup
+        if shared.receivers.load(Ordering::SeqCst) > 0 {
+            shared.notify.notify_waiters();
+        }
+    }
+
+    pub fn usage(&self) -> HistoryUsage {
+        self.shared.expire();
+        self.shared
+            .tiers
+            .iter()
+            .fold(HistoryUsage::default(), |usage, tier| HistoryUsage {
+                entries: usage.entries + tier.ring.entries.load(Ordering::Acquire),
+                bytes: usage.bytes + tier.ring.bytes.load(Ordering::Acquire),
+            })
+    }
+
+    // Everything currently retained, including the flight recorder's detailed entries
+    pub fn snapshot(&self) -> Vec<Arc<Entry>> {
+        let shared = &self.shared;
+        shared.expire();
+        let mut entries = match &shared.recorder {
+            Some(recorder) => recorder.ring.entries(),
+            None => shared
+                .tiers
+                .iter()
+                .flat_map(|tier| tier.ring.entries())
+                .collect(),
+        };
+        entries.sort_by_key(|entry| entry.seq);
+        entries
+    }
+
+    // Up to `count` recorded entries either side of a point, or `None` without a flight recorder
+    pub fn recorded(&self, around: Around, count: usize) -> Option<Vec<Arc<Entry>>> {
+        let shared = &self.shared;
+        let mut entries = shared.recorder.as_ref()?.ring.entries();
+        entries.sort_by_key(|entry| entry.seq);
+        let pivot = match around {
+            Around::Seq(seq) => entries.partition_point(|entry| entry.seq < seq),
+            Around::Time(time) => entries.partition_point(|entry| entry.timestamp < time),
+        };
+        let end = pivot.saturating_add(count).min(entries.len());
+        entries.truncate(end);
+        entries.drain(..pivot.saturating_sub(count));
+        Some(entries)
+    }
+
+    pub fn stream_id(&self) -> u64 {
+        self.shared.stream_id
     }
 
     pub fn subscribe(&self) -> Receiver {
-        Receiver {
-            buf: self.buf.read().expect("Lock poisoned").clone(),
-            rx: self.tx.subscribe(),
+        // Skip anything that's too old to be useful to a new viewer
+        self.shared.expire();
+        self.register(Ring::oldest)
+    }
+
+    // Continues from the entry with sequence number `seq`, along with how many entries since
+    // then that match `filter` have already been evicted
+    pub fn resume(&self, seq: u64, filter: Option<&Directives>) -> (Receiver, u64) {
+        let shared = &self.shared;
+        shared.expire();
+        let evicted_seq = shared
+            .tiers
+            .iter()
+            .map(|tier| tier.ring.evicted_seq.load(Ordering::Acquire))
+            .max()
+            .unwrap_or(0);
+        let receiver = self.register(|ring| ring.position_of(seq));
+        if evicted_seq <= seq {
+            return (receiver, 0);
         }
+        // Every sequence number belongs to exactly one tier, so whatever isn't retained anywhere
+        // was evicted
+        let retained = shared
+            .tiers
+            .iter()
+            .flat_map(|tier| tier.ring.entries())
+            .filter(|entry| (seq..evicted_seq).contains(&entry.seq))
+            .count() as u64;
+        let missed = (evicted_seq - seq).saturating_sub(retained);
+        let Some(filter) = filter else {
+            return (receiver, missed);
+        };
+        // Evicted too long ago to know what they were, these are assumed to match
+        let range = seq..evicted_seq;
+        let buried: Vec<_> = shared
+            .tiers
+            .iter()
+            .flat_map(|tier| tier.ring.buried(&range))
+            .collect();
+        let unknown = missed.saturating_sub(buried.len() as u64);
+        let matching = buried
+            .iter()
+            .filter(|evicted| evicted.matches(filter))
+            .count() as u64;
+        (receiver, unknown + matching)
+    }
+
+    fn register(&self, start: impl Fn(&Ring) -> u64) -> Receiver {
+        let shared = self.shared.clone();
+        let cursors = shared
+            .tiers
+            .iter()
+            .map(|tier| {
+                let cursor = Arc::new(Cursor {
+                    next: AtomicU64::new(start(&tier.ring)),
+                    paused: AtomicBool::new(false),
+                });
+                tier.ring
+                    .cursors
+                    .write()
+                    .expect("Lock poisoned")
+                    .push(cursor.clone());
+                cursor
+            })
+            .collect();
+        shared.receivers.fetch_add(1, Ordering::SeqCst);
+        Receiver { shared, cursors }
     }
 }
 
+// The receiver fell behind and this many entries were overwritten before it could read them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
 pub struct Receiver {
-    buf: VecDeque<Vec<u8>>,
-    rx: broadcast::Receiver<Vec<u8>>,
+    shared: Arc<Shared>,
+    // Next position to read from each tier
+    cursors: Vec<Arc<Cursor>>,
 }
 
 impl Receiver {
-    pub async fn recv(&mut self) -> Result<Vec<u8>, broadcast::error::RecvError> {
-        if let Some(val) = self.buf.pop_front() {
-            Ok(val)
-        } else {
-            self.rx.recv().await
+    // Senders don't wait for a paused receiver, it lags instead once it falls too far behind
+    pub fn set_paused(&self, paused: bool) {
+        for (tier, cursor) in self.shared.tiers.iter().zip(&self.cursors) {
+            cursor.paused.store(paused, Ordering::Release);
+            tier.ring.notify_progress();
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<Arc<Entry>, Lagged> {
+        let shared = self.shared.clone();
+        loop {
+            let notified = shared.notify.notified();
+            tokio::pin!(notified);
+            // Register interest before checking so a concurrent send can't be missed
+            notified.as_mut().enable();
+            if let Some(res) = self.try_recv() {
+                return res;
+            }
+            notified.await;
+        }
+    }
+
+    // Returns the entry with the lowest sequence number across all tiers
+    pub fn try_recv(&mut self) -> Option<Result<Arc<Entry>, Lagged>> {
+        'retry: loop {
+            let mut skipped = 0;
+            for (tier, cursor) in self.shared.tiers.iter().zip(&self.cursors) {
+                let next = cursor.next.load(Ordering::Acquire);
+                let oldest = tier.ring.oldest();
+                if next < oldest {
+                    skipped += oldest - next;
+                    cursor.next.store(oldest, Ordering::Release);
+                    tier.ring.notify_progress();
+                }
+            }
+            if skipped > 0 {
+                return Some(Err(Lagged(skipped)));
+            }
+
+            let mut earliest: Option<(&Tier, &Cursor, Arc<Entry>)> = None;
+            for (tier, cursor) in self.shared.tiers.iter().zip(&self.cursors) {
+                match peek(tier, cursor) {
+                    Peek::Empty => {}
+                    Peek::Ready(entry) => {
+                        if earliest
+                            .as_ref()
+                            .is_none_or(|(_, _, earliest)| entry.seq < earliest.seq)
+                        {
+                            earliest = Some((tier, cursor, entry));
+                        }
+                    }
+                    Peek::Overwritten => continue 'retry,
+                    // Wait for it so entries stay in order
+                    Peek::Unwritten => return None,
+                }
+            }
+            let (tier, cursor, entry) = earliest?;
+            // A tier that looked empty may have claimed a lower sequence number since, which
+            // is visible now that this entry is
+            for (other, other_cursor) in self.shared.tiers.iter().zip(&self.cursors) {
+                match peek(other, other_cursor) {
+                    Peek::Empty => {}
+                    Peek::Ready(other) if other.seq >= entry.seq => {}
+                    _ => continue 'retry,
+                }
+            }
+            cursor.next.store(entry.pos + 1, Ordering::Release);
+            tier.ring.notify_progress();
+            return Some(Ok(entry));
+        }
+    }
+}
+
+enum Peek {
+    // Nothing after the cursor
+    Empty,
+    Ready(Arc<Entry>),
+    // Overwritten since the lag check, it needs to be recomputed
+    Overwritten,
+    // Claimed but not written yet
+    Unwritten,
+}
+
+fn peek(tier: &Tier, cursor: &Cursor) -> Peek {
+    let next = cursor.next.load(Ordering::Acquire);
+    if next >= tier.ring.head.load(Ordering::Acquire) {
+        return Peek::Empty;
+    }
+    match &*tier.ring.slot(next).load() {
+        Some(entry) if entry.pos == next => Peek::Ready(entry.clone()),
+        Some(entry) if entry.pos > next => Peek::Overwritten,
+        _ if next < tier.ring.oldest() => Peek::Overwritten,
+        _ => Peek::Unwritten,
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.receivers.fetch_sub(1, Ordering::AcqRel);
+        for (tier, cursor) in self.shared.tiers.iter().zip(&self.cursors) {
+            tier.ring
+                .cursors
+                .write()
+                .expect("Lock poisoned")
+                .retain(|other| !Arc::ptr_eq(other, cursor));
+            tier.ring.notify_progress();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(capacity: usize) -> Settings {
+        Settings {
+            capacity,
+            retention: Vec::new(),
+            max_bytes: None,
+            max_age: None,
+            idle_capacity: None,
+            block_timeout: None,
+            spill: None,
+            recorder: None,
+        }
+    }
+
+    fn meta(level: Level) -> Option<EntryMeta> {
+        Some(EntryMeta {
+            level,
+            target: "app".to_owned(),
+        })
+    }
+
+    fn send(tx: &Sender, level: Level, count: usize) {
+        for _ in 0..count {
+            tx.send(meta(level), Bytes::from_static(b"frame"));
+        }
+    }
+
+    fn drain(rx: &mut Receiver) -> Vec<Result<u64, Lagged>> {
+        std::iter::from_fn(|| rx.try_recv())
+            .map(|res| res.map(|entry| entry.seq))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_entries_after_wrapping() {
+        let tx = channel(settings(4));
+        send(&tx, Level::Info, 10);
+
+        let seqs: Vec<_> = tx.snapshot().iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [6, 7, 8, 9]);
+        assert_eq!(tx.usage().entries, 4);
+    }
+
+    #[test]
+    fn reports_lag_then_continues_from_the_oldest_entry() {
+        let tx = channel(settings(4));
+        let mut rx = tx.subscribe();
+        send(&tx, Level::Info, 6);
+
+        assert_eq!(drain(&mut rx), [Err(Lagged(2)), Ok(2), Ok(3), Ok(4), Ok(5)]);
+    }
+
+    #[test]
+    fn merges_tiers_in_sequence_order() {
+        let tx = channel(Settings {
+            retention: vec![(Level::Warn, 4)],
+            ..settings(4)
+        });
+        let mut rx = tx.subscribe();
+        send(&tx, Level::Info, 1);
+        send(&tx, Level::Error, 1);
+        send(&tx, Level::Debug, 1);
+        send(&tx, Level::Warn, 1);
+
+        assert_eq!(drain(&mut rx), [Ok(0), Ok(1), Ok(2), Ok(3)]);
+    }
+
+    #[test]
+    fn a_stale_store_does_not_replace_a_newer_lap() {
+        let ring = Ring::new(2);
+        ring.head.store(3, Ordering::Release);
+        let entry = |seq, pos| Entry {
+            seq,
+            meta: None,
+            frame: Bytes::from_static(b"frame"),
+            pos,
+            created: Instant::now(),
+            timestamp: SystemTime::now(),
+        };
+        ring.store(entry(2, 2));
+        ring.store(entry(0, 0));
+
+        let seqs: Vec<_> = ring.entries().iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [2]);
+        assert_eq!(ring.entries.load(Ordering::Acquire), 1);
+    }
+
+    #[test]
+    fn idle_capacity_only_trims_the_base_tier() {
+        let tx = channel(Settings {
+            retention: vec![(Level::Warn, 4)],
+            idle_capacity: Some(1),
+            ..settings(4)
+        });
+        send(&tx, Level::Error, 3);
+        send(&tx, Level::Info, 3);
+
+        let seqs: Vec<_> = tx.snapshot().iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [0, 1, 2, 5]);
+    }
+
+    #[test]
+    fn expires_quiet_tiers() {
+        let tx = channel(Settings {
+            retention: vec![(Level::Warn, 4)],
+            max_age: Some(Duration::from_millis(10)),
+            ..settings(4)
+        });
+        send(&tx, Level::Error, 2);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(tx.usage().entries, 0);
+        send(&tx, Level::Info, 1);
+        let seqs: Vec<_> = tx.snapshot().iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [2]);
+    }
+
+    #[test]
+    fn finds_recorded_entries_around_a_wall_clock_time() {
+        let tx = channel(Settings {
+            recorder: Some(FlightRecorder {
+                stream_level: Level::Info,
+                capacity: 8,
+            }),
+            ..settings(4)
+        });
+        for frame in [&b"a"[..], b"b"] {
+            tx.send(meta(Level::Debug), Bytes::from_static(frame));
+        }
+        std::thread::sleep(Duration::from_millis(2));
+        let time = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(2));
+        for frame in [&b"c"[..], b"d"] {
+            tx.send(meta(Level::Debug), Bytes::from_static(frame));
+        }
+
+        let frames: Vec<_> = tx
+            .recorded(Around::Time(time), 1)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.frame.clone())
+            .collect();
+        assert_eq!(frames, [&b"b"[..], b"c"]);
+    }
+
+    #[test]
+    fn resume_counts_evicted_entries() {
+        let tx = channel(settings(4));
+        send(&tx, Level::Info, 10);
+
+        let (mut rx, missed) = tx.resume(3, None);
+        assert_eq!(missed, 3);
+        assert_eq!(drain(&mut rx), [Ok(6), Ok(7), Ok(8), Ok(9)]);
+
+        let (mut rx, missed) = tx.resume(8, None);
+        assert_eq!(missed, 0);
+        assert_eq!(drain(&mut rx), [Ok(8), Ok(9)]);
+    }
+
+    #[test]
+    fn resume_only_counts_evicted_entries_matching_the_filter() {
+        let tx = channel(settings(4));
+        send(&tx, Level::Debug, 2);
+        send(&tx, Level::Warn, 2);
+        send(&tx, Level::Debug, 4);
+
+        let filter: Directives = "warn".parse().unwrap();
+        let (_rx, missed) = tx.resume(0, Some(&filter));
+        assert_eq!(missed, 2);
+        let (_rx, missed) = tx.resume(0, None);
+        assert_eq!(missed, 4);
+    }
+
+    #[test]
+    fn merges_tiers_in_sequence_order_while_sending() {
+        let mut settings = settings(64);
+        settings.retention = vec![(Level::Warn, 64)];
+        let tx = channel(settings);
+        let mut rx = tx.subscribe();
+        let writers: Vec<_> = [Level::Info, Level::Warn]
+            .into_iter()
+            .map(|level| {
+                let tx = tx.clone();
+                std::thread::spawn(move || send(&tx, level, 32))
+            })
+            .collect();
+        let mut seen = Vec::new();
+        while seen.len() < 64 {
+            match rx.try_recv() {
+                Some(entry) => seen.push(entry.unwrap().seq),
+                None => std::thread::yield_now(),
+            }
         }
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
     }
 }
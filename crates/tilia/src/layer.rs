@@ -0,0 +1,138 @@
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Sink, Stream, TryStream};
+use tracing::span;
+use tracing::subscriber::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::record::{self, Field, FieldVisitor, Record};
+use crate::{DIAGNOSTICS_TARGET, WorkerGuard, Writer, WriterHandle};
+
+pub struct Layer<F, S, I, E, Fut>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = S>,
+    S: Stream<Item = Result<I, E>>,
+    I: TryStream<Ok = BytesMut> + Send + 'static,
+{
+    writer: Writer<F, S, I, E, Fut>,
+}
+
+impl<F, S, I, E, Fut> Layer<F, S, I, E, Fut>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = S> + Send,
+    S: Stream<Item = Result<I, E>> + Send + 'static,
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
+    E: Send + 'static,
+{
+    pub fn new(capacity: usize, make_transport: F) -> (Self, WorkerGuard) {
+        let (writer, guard) = Writer::new(capacity, make_transport);
+        (Self { writer }, guard)
+    }
+
+    pub fn disabled(make_transport: F) -> (Self, WorkerGuard) {
+        let (writer, guard) = Writer::disabled(make_transport);
+        (Self { writer }, guard)
+    }
+
+    pub fn from_writer(writer: Writer<F, S, I, E, Fut>) -> Self {
+        Self { writer }
+    }
+
+    pub fn handle(&self) -> WriterHandle {
+        self.writer.handle()
+    }
+}
+
+struct SpanFields(Vec<Field>);
+
+impl<Sub, F, S, I, E, Fut> tracing_subscriber::Layer<Sub> for Layer<F, S, I, E, Fut>
+where
+    Sub: Subscriber + for<'a> LookupSpan<'a>,
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = S> + Send + 'static,
+    S: Stream<Item = Result<I, E>> + Send + 'static,
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
+    E: Send + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, Sub>) {
+        let span = ctx.span(id).expect("Span not found");
+        let mut fields = Vec::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, Sub>) {
+        let span = ctx.span(id).expect("Span not found");
+        let mut recorded = Vec::new();
+        values.record(&mut FieldVisitor(&mut recorded));
+
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            for field in recorded {
+                if let Some(existing) = fields.iter_mut().find(|f| f.name == field.name) {
+                    existing.value = field.value;
+                } else {
+                    fields.push(field);
+                }
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, Sub>) {
+        // Always make the writer first since it lazily starts the server
+        let mut writer = self.writer.make_writer();
+
+        let meta = event.metadata();
+        // Skip building the record entirely if nobody is around to see it
+        if meta.target() == DIAGNOSTICS_TARGET
+            || !self.writer.state.demand_enabled(meta.level().into())
+        {
+            return;
+        }
+        let mut fields = Vec::new();
+        event.record(&mut FieldVisitor(&mut fields));
+
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| record::Span {
+                        name: span.name().to_owned(),
+                        target: span.metadata().target().to_owned(),
+                        fields: span
+                            .extensions()
+                            .get::<SpanFields>()
+                            .map(|fields| fields.0.clone())
+                            .unwrap_or_default(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let thread = std::thread::current();
+        let record = Record::Event(record::Event {
+            timestamp: SystemTime::now(),
+            level: meta.level().into(),
+            target: meta.target().to_owned(),
+            module_path: meta.module_path().map(ToOwned::to_owned),
+            file: meta.file().map(ToOwned::to_owned),
+            line: meta.line(),
+            thread_id: format!("{:?}", thread.id()),
+            thread_name: thread.name().map(ToOwned::to_owned),
+            spans,
+            fields,
+        });
+        writer.send_record(record);
+    }
+}
@@ -1,15 +1,32 @@
+mod auth;
+pub use auth::{EmptySecret, Secret};
 mod writer;
 pub use writer::*;
+mod layer;
+pub use layer::*;
 mod worker_guard;
 pub use worker_guard::*;
 mod filter;
+mod handle;
+pub use handle::*;
+mod dump;
+pub use dump::*;
+mod runtime;
 mod server;
 mod state;
 pub use filter::*;
 mod client;
 pub use client::*;
+pub mod directive;
 mod history;
+pub mod protocol;
+pub mod record;
+pub mod spill;
 pub mod transport;
 pub use background_service::error::BoxedError;
 pub use bytes::{Bytes, BytesMut};
 pub use transport_async;
+
+// Target of tilia's own diagnostics. They reach the app's other layers, but are kept out of tilia's
+// history so they can't push out the app's events or feed back into the writer.
+pub(crate) const DIAGNOSTICS_TARGET: &str = "tilia::diagnostics";
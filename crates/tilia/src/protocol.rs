@@ -0,0 +1,436 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use futures::future;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::record::Record;
+
+// Bump when a change can't be handled by adding defaulted fields or new message types
+pub const PROTOCOL_VERSION: u16 = 2;
+// The oldest peer version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+// How long either side waits for the other's part of the handshake
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// The interval a peer advertises is capped, it comes straight off the wire
+const MAX_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Completes once nothing has been received for the idle timeout, or never without one. A deadline
+// too far out to represent counts as no timeout.
+pub(crate) async fn idle(last_received: Instant, idle_timeout: Option<Duration>) {
+    match idle_timeout.and_then(|idle_timeout| last_received.checked_add(idle_timeout)) {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    // Directives the client wants applied before any history is sent
+    #[serde(default)]
+    pub filter: Option<String>,
+    // Sent by servers, identifies the history that sequence numbers refer to
+    #[serde(default)]
+    pub stream_id: Option<u64>,
+    // Sent by clients that saw this stream before, the sequence number to continue from
+    #[serde(default)]
+    pub resume_from: Option<u64>,
+    // How often the sender sends a heartbeat, on a fixed schedule regardless of other traffic, so
+    // the other side can tell when it goes quiet
+    #[serde(default)]
+    pub heartbeat_interval: Option<Duration>,
+    // Sent by servers that require a secret, random bytes the client has to sign with it
+    #[serde(default, with = "serde_bytes")]
+    pub challenge: Option<Vec<u8>>,
+    // Sent by clients, the server's challenge signed with the secret
+    #[serde(default, with = "serde_bytes")]
+    pub response: Option<Vec<u8>>,
+    // Sent by clients, who is connecting. Only used to tell clients apart in the server's logs, so
+    // it isn't verified.
+    #[serde(default)]
+    pub peer: Option<String>,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            filter: None,
+            stream_id: None,
+            resume_from: None,
+            heartbeat_interval: None,
+            challenge: None,
+            response: None,
+            peer: None,
+        }
+    }
+
+    pub fn with_filter(self, filter: Option<String>) -> Self {
+        Self { filter, ..self }
+    }
+
+    pub fn with_stream_id(self, stream_id: Option<u64>) -> Self {
+        Self { stream_id, ..self }
+    }
+
+    pub fn with_resume_from(self, resume_from: Option<u64>) -> Self {
+        Self {
+            resume_from,
+            ..self
+        }
+    }
+
+    pub fn with_heartbeat_interval(self, heartbeat_interval: Option<Duration>) -> Self {
+        Self {
+            heartbeat_interval,
+            ..self
+        }
+    }
+
+    pub fn with_challenge(self, challenge: Option<Vec<u8>>) -> Self {
+        Self { challenge, ..self }
+    }
+
+    pub fn with_response(self, response: Option<Vec<u8>>) -> Self {
+        Self { response, ..self }
+    }
+
+    pub fn with_peer(self, peer: Option<String>) -> Self {
+        Self { peer, ..self }
+    }
+
+    // Three missed heartbeats, if the sender of this hello sends any
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        self.heartbeat_interval
+            .map(|interval| interval.min(MAX_HEARTBEAT_INTERVAL).saturating_mul(3))
+    }
+
+    pub fn negotiate(&self, remote: &Hello) -> Result<u16, ProtocolError> {
+        let version = self.version.min(remote.version);
+        if version < self.min_version || version < remote.min_version {
+            Err(ProtocolError::Incompatible {
+                local: Box::new(self.clone()),
+                remote: Box::new(remote.clone()),
+            })
+        } else {
+            Ok(version)
+        }
+    }
+}
+
+// Everything defaults so fields can be added without breaking older peers
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub pid: u32,
+    pub hostname: Option<String>,
+    pub started_at: Option<SystemTime>,
+    pub tilia_version: String,
+    pub history_entries: usize,
+    pub history_bytes: usize,
+}
+
+// Identifies this process to a server, e.g. `tilia-console (pid 42) on build-box`
+pub(crate) fn peer_name() -> String {
+    let name = executable_name().unwrap_or_else(|| "unknown".to_owned());
+    let pid = std::process::id();
+    match sysinfo::System::host_name() {
+        Some(hostname) => format!("{name} (pid {pid}) on {hostname}"),
+        None => format!("{name} (pid {pid})"),
+    }
+}
+
+impl AppInfo {
+    // The name defaults to the executable's
+    pub(crate) fn current(name: Option<String>, version: Option<String>) -> Self {
+        Self {
+            name: name.or_else(executable_name),
+            version,
+            pid: std::process::id(),
+            hostname: sysinfo::System::host_name(),
+            started_at: process_started_at(),
+            tilia_version: env!("CARGO_PKG_VERSION").to_owned(),
+            ..Self::default()
+        }
+    }
+}
+
+fn executable_name() -> Option<String> {
+    let exe = std::env::current_exe().ok()?;
+    Some(exe.file_stem()?.to_string_lossy().into_owned())
+}
+
+// The writer may be built long after the process started
+fn process_started_at() -> Option<SystemTime> {
+    let pid = sysinfo::get_current_pid().ok()?;
+    let mut system = sysinfo::System::new();
+    system.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]), false);
+    let started_at = system.process(pid)?.start_time();
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(started_at))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Message {
+    Hello(Hello),
+    Record(Record),
+    Command {
+        id: u64,
+        command: Command,
+    },
+    Reply {
+        id: u64,
+        reply: Reply,
+    },
+    Goodbye {
+        reason: String,
+    },
+    // The client fell behind and this many events were skipped
+    Dropped {
+        count: u64,
+    },
+    // Sequence number of the next record, with each one after it counting up. `None` if the
+    // records that follow aren't part of the live stream, like replies to a replay.
+    Sequence {
+        next: Option<u64>,
+    },
+    // Lets the client know the connection is still alive
+    Heartbeat,
+    // Ends the handshake with servers that require a secret once the client's response checks
+    // out, a wrong or missing response gets a `Goodbye` instead
+    Authenticated,
+    // Describes the app, sent once after the handshake
+    AppInfo(AppInfo),
+    // A record from the flight recorder, sent in reply to `Command::Recorded`. Kept apart from
+    // `Record` so clients can tell it from live output.
+    Recorded(Record),
+    // Sent by a newer peer, safe to ignore
+    #[serde(skip)]
+    Unknown(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    Ping,
+    Pause,
+    Resume,
+    // Replay the server's history buffer, followed by live events
+    History,
+    // Only forward events matching these directives, or everything if `None`
+    SetFilter(Option<String>),
+    // Replace the app's capture filter for every client, or restore the original if `None`
+    SetCaptureFilter(Option<String>),
+    // Replay everything in the app's spill files, including previous runs
+    ReplaySpill,
+    // Send up to `count` events either side of a point from the flight recorder, including the
+    // detailed ones that aren't streamed
+    Recorded { around: Around, count: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Around {
+    Seq(u64),
+    Time(SystemTime),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Reply {
+    Pong,
+    Ok,
+    Error(String),
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct CommandEnvelope {
+    data: CommandId,
+}
+
+#[derive(Deserialize)]
+struct CommandId {
+    id: u64,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("Failed to encode message")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        match rmp_serde::from_slice(bytes) {
+            Ok(message) => Ok(message),
+            Err(e) => match rmp_serde::from_slice::<Envelope>(bytes) {
+                Ok(envelope) if !Self::is_known(&envelope.kind) => Ok(Self::Unknown(envelope.kind)),
+                _ => Err(ProtocolError::Decode(e)),
+            },
+        }
+    }
+
+    // Recovers the id of a command this build doesn't understand so it can still be answered
+    pub fn command_id(bytes: &[u8]) -> Option<u64> {
+        match rmp_serde::from_slice::<Envelope>(bytes) {
+            Ok(envelope) if envelope.kind == "Command" => {
+                rmp_serde::from_slice::<CommandEnvelope>(bytes)
+                    .ok()
+                    .map(|envelope| envelope.data.id)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether a frame that failed to decode was meant to be a record, like one holding a kind of
+    // record added by a newer peer
+    pub fn is_record(bytes: &[u8]) -> bool {
+        rmp_serde::from_slice::<Envelope>(bytes).is_ok_and(|envelope| envelope.kind == "Record")
+    }
+
+    fn is_known(kind: &str) -> bool {
+        matches!(
+            kind,
+            "Hello"
+                | "Record"
+                | "Command"
+                | "Reply"
+                | "Goodbye"
+                | "Dropped"
+                | "Sequence"
+                | "Heartbeat"
+                | "AppInfo"
+                | "Recorded"
+                | "Authenticated"
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    // Boxed to keep every `Result` carrying this error small
+    Incompatible {
+        local: Box<Hello>,
+        remote: Box<Hello>,
+    },
+    UnexpectedMessage(&'static str),
+    Rejected(String),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incompatible { local, remote } => write!(
+                f,
+                "incompatible protocol versions: local supports {}-{}, remote supports {}-{}",
+                local.min_version, local.version, remote.min_version, remote.version
+            ),
+            Self::UnexpectedMessage(expected) => {
+                write!(f, "unexpected message, expected {expected}")
+            }
+            Self::Rejected(reason) => write!(f, "connection rejected by remote: {reason}"),
+            Self::Decode(e) => write!(f, "failed to decode message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ProtocolError {
+    // Retrying won't help if the peers can't agree on a version
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Incompatible { .. } | Self::Rejected(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A message in the shape a newer peer would send
+    #[derive(Serialize)]
+    struct Frame<T> {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        data: T,
+    }
+
+    #[derive(Serialize)]
+    struct UnknownCommand {
+        id: u64,
+        command: &'static str,
+    }
+
+    fn encode<T: Serialize>(kind: &'static str, data: T) -> Vec<u8> {
+        rmp_serde::to_vec_named(&Frame { kind, data }).unwrap()
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let message = Message::Goodbye {
+            reason: "done".to_owned(),
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn decodes_unknown_kinds_as_unknown() {
+        let frame = encode("Teleport", 1);
+        assert_eq!(
+            Message::decode(&frame).unwrap(),
+            Message::Unknown("Teleport".to_owned())
+        );
+    }
+
+    #[test]
+    fn known_kinds_that_fail_to_decode_are_errors() {
+        let frame = encode("Goodbye", "many");
+        assert!(matches!(
+            Message::decode(&frame),
+            Err(ProtocolError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn recovers_the_id_of_unknown_commands() {
+        let frame = encode(
+            "Command",
+            UnknownCommand {
+                id: 7,
+                command: "Teleport",
+            },
+        );
+        assert!(Message::decode(&frame).is_err());
+        assert_eq!(Message::command_id(&frame), Some(7));
+    }
+
+    #[test]
+    fn caps_the_idle_timeout_of_huge_heartbeat_intervals() {
+        let hello = Hello::current().with_heartbeat_interval(Some(Duration::MAX));
+        assert_eq!(hello.idle_timeout(), Some(MAX_HEARTBEAT_INTERVAL * 3));
+        assert_eq!(Hello::current().idle_timeout(), None);
+    }
+
+    #[test]
+    fn recognizes_records_that_fail_to_decode() {
+        let frame = encode("Record", ("Hologram", 1));
+        assert!(Message::decode(&frame).is_err());
+        assert!(Message::is_record(&frame));
+        assert!(!Message::is_record(&encode("Dropped", "many")));
+    }
+}
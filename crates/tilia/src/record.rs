@@ -0,0 +1,351 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Record {
+    Event(Event),
+    // Pre-formatted output from a `Writer`
+    Text(#[serde(with = "serde_bytes")] Vec<u8>),
+    // Output from a `Writer` that can't be shown as text. Peers older than protocol version 2 get
+    // it as `Text` instead.
+    Binary(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl Record {
+    // Wraps a `Writer`'s output, flagging it if it isn't text
+    pub(crate) fn output(payload: Vec<u8>) -> Self {
+        if is_binary(&payload) {
+            Self::Binary(payload)
+        } else {
+            Self::Text(payload)
+        }
+    }
+}
+
+// Payloads that can't be shown as text, because they aren't UTF-8 or contain control characters
+// other than whitespace and ANSI escapes
+fn is_binary(payload: &[u8]) -> bool {
+    std::str::from_utf8(payload).is_err()
+        || payload
+            .iter()
+            .any(|&byte| byte.is_ascii_control() && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x1b))
+}
+
+// Ordered by severity, so `Level::Trace < Level::Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trace => "TRACE",
+            Self::Debug => "DEBUG",
+            Self::Info => "INFO",
+            Self::Warn => "WARN",
+            Self::Error => "ERROR",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Trace,
+            Self::Debug,
+            Self::Info,
+            Self::Warn,
+            Self::Error,
+        ]
+        .into_iter()
+        .find(|level| level.as_str().eq_ignore_ascii_case(s))
+        .ok_or(ParseLevelError)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseLevelError;
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected one of trace, debug, info, warn or error")
+    }
+}
+
+impl std::error::Error for ParseLevelError {}
+
+impl From<&tracing::Level> for Level {
+    fn from(level: &tracing::Level) -> Self {
+        match *level {
+            tracing::Level::TRACE => Self::Trace,
+            tracing::Level::DEBUG => Self::Debug,
+            tracing::Level::INFO => Self::Info,
+            tracing::Level::WARN => Self::Warn,
+            tracing::Level::ERROR => Self::Error,
+        }
+    }
+}
+
+impl From<Level> for tracing::Level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => Self::TRACE,
+            Level::Debug => Self::DEBUG,
+            Level::Info => Self::INFO,
+            Level::Warn => Self::WARN,
+            Level::Error => Self::ERROR,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F64(f64),
+    Str(String),
+    Debug(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(val) => val.fmt(f),
+            Self::I64(val) => val.fmt(f),
+            Self::U64(val) => val.fmt(f),
+            Self::I128(val) => val.fmt(f),
+            Self::U128(val) => val.fmt(f),
+            Self::F64(val) => val.fmt(f),
+            Self::Str(val) | Self::Debug(val) => val.fmt(f),
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Self::Str(String::new())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Field {
+    pub name: String,
+    pub value: Value,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Span {
+    pub name: String,
+    pub target: String,
+    pub fields: Vec<Field>,
+}
+
+// The name followed by any fields in braces, like `request{id=1}`
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some((first, rest)) = self.fields.split_first() {
+            write!(f, "{{{first}")?;
+            for field in rest {
+                write!(f, " {field}")?;
+            }
+            f.write_str("}")?;
+        }
+        Ok(())
+    }
+}
+
+// Fields missing from the wire fall back to their defaults, so new ones can be added without
+// breaking older peers
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Event {
+    pub timestamp: SystemTime,
+    pub level: Level,
+    pub target: String,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub thread_id: String,
+    pub thread_name: Option<String>,
+    pub spans: Vec<Span>,
+    pub fields: Vec<Field>,
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self {
+            timestamp: SystemTime::UNIX_EPOCH,
+            level: Level::Info,
+            target: String::new(),
+            module_path: None,
+            file: None,
+            line: None,
+            thread_id: String::new(),
+            thread_name: None,
+            spans: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl Event {
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| &field.value)
+    }
+
+    pub fn message(&self) -> Option<&Value> {
+        self.field("message")
+    }
+
+    // Every field except the message, which is usually shown on its own
+    pub fn other_fields(&self) -> impl Iterator<Item = &Field> {
+        self.fields.iter().filter(|field| field.name != "message")
+    }
+}
+
+// One line with the level, spans, target, message and other fields. The timestamp is left out so
+// it can be formatted to suit where the line is shown.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5} ", self.level)?;
+        for span in &self.spans {
+            write!(f, "{span}:")?;
+        }
+        if !self.spans.is_empty() {
+            f.write_str(" ")?;
+        }
+        write!(f, "{}:", self.target)?;
+        if let Some(message) = self.message() {
+            write!(f, " {message}")?;
+        }
+        for field in self.other_fields() {
+            write!(f, " {field}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct FieldVisitor<'a>(pub(crate) &'a mut Vec<Field>);
+
+impl FieldVisitor<'_> {
+    fn push(&mut self, field: &tracing::field::Field, value: Value) {
+        self.0.push(Field {
+            name: field.name().to_owned(),
+            value,
+        });
+    }
+}
+
+impl tracing::field::Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.push(field, Value::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.push(field, Value::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.push(field, Value::U64(value));
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        self.push(field, Value::I128(value));
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        self.push(field, Value::U128(value));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.push(field, Value::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.push(field, Value::Str(value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        self.push(field, Value::Debug(format!("{value:?}")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, value: Value) -> Field {
+        Field {
+            name: name.to_owned(),
+            value,
+        }
+    }
+
+    #[test]
+    fn formats_events_on_one_line() {
+        let event = Event {
+            level: Level::Warn,
+            target: "app::db".to_owned(),
+            spans: vec![
+                Span {
+                    name: "request".to_owned(),
+                    fields: vec![field("id", Value::U64(7))],
+                    ..Span::default()
+                },
+                Span {
+                    name: "query".to_owned(),
+                    ..Span::default()
+                },
+            ],
+            fields: vec![
+                field("message", Value::Str("slow query".to_owned())),
+                field("ms", Value::U64(1200)),
+            ],
+            ..Event::default()
+        };
+
+        assert_eq!(
+            event.to_string(),
+            " WARN request{id=7}:query: app::db: slow query ms=1200"
+        );
+    }
+
+    #[test]
+    fn flags_binary_output() {
+        let text = b"\x1b[1mbold\x1b[0m\tdone\n".to_vec();
+        assert_eq!(Record::output(text.clone()), Record::Text(text));
+        let binary = vec![0, 159, 146, 150];
+        assert_eq!(Record::output(binary.clone()), Record::Binary(binary));
+    }
+}
@@ -0,0 +1,35 @@
+use std::io;
+
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+
+// A private runtime driven by its own thread, for applications that don't run inside tokio
+pub(crate) struct DedicatedRuntime {
+    handle: Handle,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl DedicatedRuntime {
+    pub(crate) fn new() -> io::Result<Self> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = rt.handle().clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        std::thread::Builder::new()
+            .name("tilia".to_owned())
+            .spawn(move || {
+                // The runtime is dropped on this thread once the writer state goes away, since
+                // dropping it from inside another runtime would panic
+                let _ = rt.block_on(shutdown_rx);
+            })?;
+        Ok(Self {
+            handle,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub(crate) fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
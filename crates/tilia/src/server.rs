@@ -1,10 +1,36 @@
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
 use background_service::error::BoxedError;
 use background_service::{BackgroundService, ServiceContext};
-use bytes::Bytes;
-use futures::{Sink, SinkExt, Stream, StreamExt};
+use bytes::{Bytes, BytesMut};
+use futures::{FutureExt as _, Sink, SinkExt, Stream, StreamExt, TryStream, TryStreamExt, future};
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
 use tokio_util::future::FutureExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::{self, Secret};
+use crate::directive::{Directives, ParseError};
+use crate::history::{self, Entry, Lagged};
+use crate::protocol::{
+    AppInfo, Command, HANDSHAKE_TIMEOUT, Hello, Message, ProtocolError, Reply, idle,
+};
+use crate::record::Record;
+use crate::state::State;
+use crate::{DIAGNOSTICS_TARGET, SlowConsumerPolicy, spill};
 
-use crate::history;
+// Failed authentication is only logged this often, a misconfigured client retries in a loop
+const AUTH_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+// Older peers get binary records as text
+const BINARY_RECORD_VERSION: u16 = 2;
+// How long a send may stall for clients that don't send heartbeats
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub(crate) struct RequestHandler<S, I, E>
 where
@@ -12,21 +38,50 @@ where
 {
     tx: history::Sender,
     transport: S,
+    state: Arc<State>,
+    settings: ServerSettings,
+    drain: CancellationToken,
+    auth_warnings: Arc<AuthWarnings>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ServerSettings {
+    pub(crate) slow_consumer_policy: SlowConsumerPolicy,
+    pub(crate) spill_dir: Option<PathBuf>,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) app_info: AppInfo,
+    pub(crate) secret: Option<Secret>,
+    pub(crate) remote_capture_filter: bool,
 }
 
 impl<S, I, E> RequestHandler<S, I, E>
 where
     S: Stream<Item = Result<I, E>>,
 {
-    pub(crate) fn new(transport: S, tx: history::Sender) -> Self {
-        Self { tx, transport }
+    pub(crate) fn new(
+        transport: S,
+        tx: history::Sender,
+        state: Arc<State>,
+        settings: ServerSettings,
+        drain: CancellationToken,
+    ) -> Self {
+        Self {
+            tx,
+            transport,
+            state,
+            settings,
+            drain,
+            auth_warnings: Arc::default(),
+        }
     }
 }
 
 impl<S, I, E> BackgroundService for RequestHandler<S, I, E>
 where
     S: Stream<Item = Result<I, E>> + Send,
-    I: Sink<Bytes> + Unpin + Send + 'static,
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
 {
     fn name(&self) -> &str {
         "request_handler"
@@ -35,19 +90,25 @@ where
     async fn run(self, context: ServiceContext) -> Result<(), BoxedError> {
         let transport = self.transport;
         futures::pin_mut!(transport);
-        while let Some(Some(Ok(mut client))) = transport
+        while let Some(Some(Ok(client))) = transport
             .next()
             .with_cancellation_token(context.cancellation_token())
             .await
         {
-            let mut rx = self.tx.subscribe();
-            context.spawn(("request", |context: ServiceContext| async move {
-                while let Some(Ok(msg)) = rx
-                    .recv()
-                    .with_cancellation_token(context.cancellation_token())
-                    .await
-                {
-                    let _ = client.send(Bytes::from(msg)).await;
+            let tx = self.tx.clone();
+            let state = self.state.clone();
+            let settings = self.settings.clone();
+            let drain = self.drain.clone();
+            let auth_warnings = self.auth_warnings.clone();
+            context.spawn(("request", move |context: ServiceContext| async move {
+                let mut client = Bounded::new(client, HANDSHAKE_TIMEOUT);
+                let hello = Hello::current()
+                    .with_stream_id(Some(tx.stream_id()))
+                    .with_heartbeat_interval(settings.heartbeat_interval);
+                let secret = settings.secret.as_ref();
+                if let Ok(session) = handshake(&mut client, hello, secret, &auth_warnings).await {
+                    let _viewer = ViewerGuard::new(state.clone());
+                    serve(client, session, tx, state, settings, drain, context).await;
                 }
                 Ok(())
             }));
@@ -55,3 +116,642 @@ where
         Ok(())
     }
 }
+
+// Held for as long as a client is connected to the server
+struct ViewerGuard {
+    state: Arc<State>,
+}
+
+impl ViewerGuard {
+    fn new(state: Arc<State>) -> Self {
+        state.viewer_connected();
+        Self { state }
+    }
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        self.state.viewer_disconnected();
+    }
+}
+
+struct Session {
+    version: u16,
+    // Only clients that send heartbeats can be expected to send something regularly
+    idle_timeout: Option<Duration>,
+    filter: Option<Directives>,
+    resume_from: Option<u64>,
+}
+
+async fn serve<I>(
+    mut client: Bounded<I>,
+    session: Session,
+    tx: history::Sender,
+    state: Arc<State>,
+    settings: ServerSettings,
+    drain: CancellationToken,
+    context: ServiceContext,
+) where
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
+{
+    let cancellation_token = context.cancellation_token();
+    client.timeout = session.idle_timeout.unwrap_or(SEND_TIMEOUT);
+    let usage = tx.usage();
+    let app_info = AppInfo {
+        history_entries: usage.entries,
+        history_bytes: usage.bytes,
+        ..settings.app_info.clone()
+    };
+    if send(&mut client, &Message::AppInfo(app_info))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let (mut rx, missed) = match session.resume_from {
+        Some(seq) => tx.resume(seq, session.filter.as_ref()),
+        None => (tx.subscribe(), 0),
+    };
+    if missed > 0
+        && send(&mut client, &Message::Dropped { count: missed })
+            .await
+            .is_err()
+    {
+        return;
+    }
+    let version = session.version;
+    let idle_timeout = session.idle_timeout;
+    let mut paused = false;
+    let mut filter = session.filter;
+    // The sequence number the client will assume for the next record
+    let mut next_seq = None;
+    let mut heartbeat = settings.heartbeat_interval.map(|interval| {
+        let mut heartbeat = tokio::time::interval_at(Instant::now() + interval, interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat
+    });
+    let mut last_received = Instant::now();
+    loop {
+        tokio::select! {
+            entry = rx.recv(), if !paused => match entry {
+                Ok(entry) => {
+                    let filter = filter.as_ref();
+                    let res = send_entry(&mut client, &entry, version, filter, &mut next_seq).await;
+                    if res.is_err() {
+                        return;
+                    }
+                }
+                Err(Lagged(count)) => {
+                    if settings.slow_consumer_policy == SlowConsumerPolicy::Disconnect {
+                        let reason = format!("client fell behind by {count} events");
+                        let _ = send(&mut client, &Message::Goodbye { reason }).await;
+                        return;
+                    }
+                    if send(&mut client, &Message::Dropped { count }).await.is_err() {
+                        return;
+                    }
+                }
+            },
+            frame = client.try_next().map(|frame| frame.ok().flatten()) => {
+                let Some(frame) = frame else {
+                    return;
+                };
+                last_received = Instant::now();
+                match Message::decode(&frame) {
+                    Ok(Message::Command { id, command }) => {
+                        let reply = match command {
+                            Command::Ping => Reply::Pong,
+                            Command::Pause => {
+                                paused = true;
+                                rx.set_paused(true);
+                                Reply::Ok
+                            }
+                            Command::Resume => {
+                                paused = false;
+                                rx.set_paused(false);
+                                Reply::Ok
+                            }
+                            Command::History => {
+                                rx = tx.subscribe();
+                                rx.set_paused(paused);
+                                Reply::Ok
+                            }
+                            Command::SetFilter(directives) => match parse_filter(directives) {
+                                Ok(directives) => {
+                                    filter = directives;
+                                    Reply::Ok
+                                }
+                                Err(e) => Reply::Error(e.to_string()),
+                            },
+                            Command::SetCaptureFilter(_) if !settings.remote_capture_filter => {
+                                Reply::Error("changing the capture filter is disabled".to_owned())
+                            }
+                            Command::SetCaptureFilter(directives) => {
+                                match parse_filter(directives) {
+                                    Ok(directives) => {
+                                        state.set_capture_filter(directives);
+                                        Reply::Ok
+                                    }
+                                    Err(e) => Reply::Error(e.to_string()),
+                                }
+                            }
+                            Command::Recorded { around, count } => {
+                                let entries = tx.recorded(around, count);
+                                let filter = filter.as_ref();
+                                match send_recorded(&mut client, entries, version, filter).await {
+                                    Ok(()) => Reply::Ok,
+                                    Err(e) => Reply::Error(e.to_string()),
+                                }
+                            }
+                            Command::ReplaySpill => {
+                                let dir = settings.spill_dir.as_deref();
+                                let filter = filter.as_ref();
+                                let seq = &mut next_seq;
+                                let res = replay_spill(&mut client, dir, version, filter, seq);
+                                match res.await {
+                                    Ok(()) => Reply::Ok,
+                                    Err(e) => Reply::Error(e.to_string()),
+                                }
+                            }
+                        };
+                        if send(&mut client, &Message::Reply { id, reply }).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Message::Goodbye { .. }) => return,
+                    Ok(_) => {}
+                    Err(_) => {
+                        if let Some(id) = Message::command_id(&frame) {
+                            let reply = Reply::Error("unsupported command".to_owned());
+                            if send(&mut client, &Message::Reply { id, reply }).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+            _ = idle(last_received, idle_timeout) => {
+                let reason = "client stopped responding".to_owned();
+                let _ = send(&mut client, &Message::Goodbye { reason }).await;
+                return;
+            }
+            _ = tick(&mut heartbeat) => {
+                if send(&mut client, &Message::Heartbeat).await.is_err() {
+                    return;
+                }
+            }
+            _ = drain.cancelled() => {
+                // Paused clients still get the last events, they won't get another chance
+                let filter = filter.as_ref();
+                let _ = flush(&mut client, &mut rx, version, filter, &mut next_seq).await;
+                let reason = "server is shutting down".to_owned();
+                let _ = send(&mut client, &Message::Goodbye { reason }).await;
+                let _ = client.close().await;
+                return;
+            }
+            _ = cancellation_token.cancelled() => return,
+        }
+    }
+}
+
+// Gives up on sends that stall for longer than the timeout. A half-open connection would otherwise
+// block the whole select loop, including the idle, drain and cancellation branches.
+struct Bounded<I> {
+    inner: I,
+    timeout: Duration,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+#[derive(Debug)]
+enum SendError<E> {
+    TimedOut,
+    Inner(E),
+}
+
+impl<I> Bounded<I> {
+    fn new(inner: I, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    // The deadline is armed by the first poll that can't make progress and cleared once one can
+    fn bound<E>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<Result<(), E>>,
+    ) -> Poll<Result<(), SendError<E>>> {
+        if let Poll::Ready(res) = poll {
+            self.deadline = None;
+            return Poll::Ready(res.map_err(SendError::Inner));
+        }
+        let timeout = self.timeout;
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.deadline = None;
+                Poll::Ready(Err(SendError::TimedOut))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<I> Stream for Bounded<I>
+where
+    I: TryStream + Unpin,
+{
+    type Item = Result<I::Ok, I::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).try_poll_next(cx)
+    }
+}
+
+impl<I> Sink<Bytes> for Bounded<I>
+where
+    I: Sink<Bytes> + Unpin,
+{
+    type Error = SendError<I::Error>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_ready(cx);
+        self.bound(cx, poll)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner)
+            .start_send(item)
+            .map_err(SendError::Inner)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.bound(cx, poll)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = Pin::new(&mut self.inner).poll_close(cx);
+        self.bound(cx, poll)
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+// Sends whatever the client hasn't received yet without waiting for anything new
+async fn flush<I>(
+    client: &mut I,
+    rx: &mut history::Receiver,
+    version: u16,
+    filter: Option<&Directives>,
+    next_seq: &mut Option<u64>,
+) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    while let Some(entry) = rx.try_recv() {
+        match entry {
+            Ok(entry) => send_entry(client, &entry, version, filter, next_seq).await?,
+            Err(Lagged(count)) => send(client, &Message::Dropped { count }).await?,
+        }
+    }
+    Ok(())
+}
+
+// Only tells the client the entry's sequence number if it can't work it out from the last one
+async fn send_entry<I>(
+    client: &mut I,
+    entry: &Entry,
+    version: u16,
+    filter: Option<&Directives>,
+    next_seq: &mut Option<u64>,
+) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    if filter.is_some_and(|filter| !entry.matches(filter)) {
+        return Ok(());
+    }
+    if *next_seq != Some(entry.seq) {
+        send(
+            client,
+            &Message::Sequence {
+                next: Some(entry.seq),
+            },
+        )
+        .await?;
+    }
+    *next_seq = Some(entry.seq + 1);
+    client
+        .send(downgrade_frame(&entry.frame, version))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}")).into())
+}
+
+// Marks the records that follow as outside the live stream
+async fn leave_stream<I>(client: &mut I, next_seq: &mut Option<u64>) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    if next_seq.take().is_some() {
+        send(client, &Message::Sequence { next: None }).await?;
+    }
+    Ok(())
+}
+
+async fn send_recorded<I>(
+    client: &mut I,
+    entries: Option<Vec<Arc<Entry>>>,
+    version: u16,
+    filter: Option<&Directives>,
+) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    let Some(entries) = entries else {
+        return Err("the flight recorder is not enabled".into());
+    };
+    for entry in entries {
+        if filter.is_some_and(|filter| !entry.matches(filter)) {
+            continue;
+        }
+        if let Ok(Message::Record(record)) = Message::decode(&entry.frame) {
+            send(client, &Message::Recorded(downgrade(record, version))).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn replay_spill<I>(
+    client: &mut I,
+    spill_dir: Option<&Path>,
+    version: u16,
+    filter: Option<&Directives>,
+    next_seq: &mut Option<u64>,
+) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    let Some(spill_dir) = spill_dir else {
+        return Err("spilling is not enabled".into());
+    };
+    let spill_dir = spill_dir.to_owned();
+    let frames = tokio::task::spawn_blocking(move || spill::read_segments(spill_dir)).await??;
+    let mut frames = spill::stream_frames(frames);
+    leave_stream(client, next_seq).await?;
+    while let Some(frame) = frames.recv().await {
+        let frame = frame?;
+        // Spilled frames don't keep their metadata, so the record has to be decoded to filter it
+        if let Some(filter) = filter
+            && let Ok(Message::Record(Record::Event(event))) = Message::decode(&frame)
+            && !filter.enabled(&event.target, event.level)
+        {
+            continue;
+        }
+        client
+            .send(downgrade_frame(&frame, version))
+            .await
+            .map_err(|e| io::Error::other(format!("{e:?}")))?;
+    }
+    Ok(())
+}
+
+fn downgrade(record: Record, version: u16) -> Record {
+    match record {
+        Record::Binary(payload) if version < BINARY_RECORD_VERSION => Record::Text(payload),
+        record => record,
+    }
+}
+
+// Only decodes the frame for peers that need it rewritten
+fn downgrade_frame(frame: &Bytes, version: u16) -> Bytes {
+    if version >= BINARY_RECORD_VERSION {
+        return frame.clone();
+    }
+    match Message::decode(frame) {
+        Ok(Message::Record(record @ Record::Binary(_))) => {
+            Bytes::from(Message::Record(downgrade(record, version)).encode())
+        }
+        _ => frame.clone(),
+    }
+}
+
+fn parse_filter(directives: Option<String>) -> Result<Option<Directives>, ParseError> {
+    let directives = directives
+        .map(|directives| directives.parse())
+        .transpose()?;
+    Ok(directives.filter(|directives: &Directives| !directives.is_empty()))
+}
+
+async fn handshake<I>(
+    client: &mut I,
+    local: Hello,
+    secret: Option<&Secret>,
+    auth_warnings: &AuthWarnings,
+) -> Result<Session, BoxedError>
+where
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
+{
+    let local = local.with_challenge(secret.map(|_| auth::challenge()));
+    send(client, &Message::Hello(local.clone())).await?;
+
+    let frame = tokio::time::timeout(HANDSHAKE_TIMEOUT, client.try_next())
+        .await?
+        .map_err(|e| io::Error::other(format!("{e:?}")))?
+        .ok_or(io::Error::new(io::ErrorKind::NotConnected, "end of stream"))?;
+
+    match Message::decode(&frame)? {
+        Message::Hello(remote) => {
+            let version = match local.negotiate(&remote) {
+                Ok(version) => version,
+                Err(e) => return Err(reject(client, e).await),
+            };
+            if let Some(secret) = secret {
+                if !authenticated(secret, &local, &remote) {
+                    auth_warnings.warn(remote.peer.as_deref());
+                    let e =
+                        io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed");
+                    return Err(reject(client, e).await);
+                }
+                send(client, &Message::Authenticated).await?;
+            }
+            let idle_timeout = remote.idle_timeout();
+            match parse_filter(remote.filter) {
+                Ok(filter) => Ok(Session {
+                    version,
+                    idle_timeout,
+                    filter,
+                    resume_from: remote.resume_from,
+                }),
+                Err(e) => Err(reject(client, e).await),
+            }
+        }
+        Message::Goodbye { reason } => Err(ProtocolError::Rejected(reason).into()),
+        _ => Err(ProtocolError::UnexpectedMessage("hello").into()),
+    }
+}
+
+#[derive(Debug, Default)]
+struct AuthWarnings {
+    last: Mutex<Option<Instant>>,
+    // Failures since the last warning
+    suppressed: AtomicU64,
+}
+
+impl AuthWarnings {
+    // Kept out of tilia's own history, so a client retrying with the wrong secret doesn't fill it
+    fn warn(&self, peer: Option<&str>) {
+        let mut last = self.last.lock().expect("Lock poisoned");
+        if last.is_some_and(|last| last.elapsed() < AUTH_WARNING_INTERVAL) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        *last = Some(Instant::now());
+        let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+        tracing::warn!(
+            target: DIAGNOSTICS_TARGET,
+            peer = peer.unwrap_or("unknown"),
+            suppressed,
+            "rejected a client that failed to authenticate"
+        );
+    }
+}
+
+fn authenticated(secret: &Secret, local: &Hello, remote: &Hello) -> bool {
+    match (&local.challenge, &remote.response) {
+        (Some(challenge), Some(response)) => secret.verify(challenge, response),
+        _ => false,
+    }
+}
+
+async fn reject<I>(client: &mut I, error: impl Into<BoxedError>) -> BoxedError
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    let error = error.into();
+    let reason = error.to_string();
+    let _ = send(client, &Message::Goodbye { reason }).await;
+    error
+}
+
+async fn send<I>(client: &mut I, message: &Message) -> Result<(), BoxedError>
+where
+    I: Sink<Bytes> + Unpin,
+    <I as Sink<Bytes>>::Error: Debug,
+{
+    client
+        .send(Bytes::from(message.encode()))
+        .await
+        .map_err(|e| io::Error::other(format!("{e:?}")).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    use super::*;
+
+    type Peer = Framed<DuplexStream, LengthDelimitedCodec>;
+
+    fn pair() -> (Peer, Peer) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        (
+            Framed::new(server, LengthDelimitedCodec::new()),
+            Framed::new(client, LengthDelimitedCodec::new()),
+        )
+    }
+
+    async fn receive(client: &mut Peer) -> Option<Message> {
+        let frame = client.try_next().await.unwrap()?;
+        Some(Message::decode(&frame).unwrap())
+    }
+
+    // Plays the client's side of the handshake, answering the challenge with `secret` if given.
+    // Returns everything the server sent after its hello.
+    async fn connect(server_secret: &str, client_secret: Option<&str>) -> Vec<Message> {
+        let (mut server, mut client) = pair();
+        let server_secret = Secret::new(server_secret).unwrap();
+        let handshake = tokio::spawn(async move {
+            let warnings = AuthWarnings::default();
+            let session = handshake(
+                &mut server,
+                Hello::current(),
+                Some(&server_secret),
+                &warnings,
+            );
+            // Anything the server sends once the handshake is over comes after this
+            let res = session.await.map(|_| ());
+            if res.is_ok() {
+                send(&mut server, &Message::AppInfo(AppInfo::default()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let Some(Message::Hello(remote)) = receive(&mut client).await else {
+            panic!("expected a hello");
+        };
+        let challenge = remote.challenge.expect("expected a challenge");
+        let response = client_secret.map(|secret| Secret::new(secret).unwrap().respond(&challenge));
+        let hello = Hello::current().with_response(response);
+        send(&mut client, &Message::Hello(hello)).await.unwrap();
+
+        handshake.await.unwrap();
+        let mut received = Vec::new();
+        while let Some(message) = receive(&mut client).await {
+            received.push(message);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn authenticates_clients_with_the_secret_before_anything_else() {
+        let received = connect("hunter2", Some("hunter2")).await;
+        assert!(matches!(
+            received.as_slice(),
+            [Message::Authenticated, Message::AppInfo(_)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn says_goodbye_to_clients_with_the_wrong_secret() {
+        let received = connect("hunter2", Some("hunter3")).await;
+        assert!(matches!(received.as_slice(), [Message::Goodbye { .. }]));
+    }
+
+    #[tokio::test]
+    async fn says_goodbye_to_clients_without_a_secret() {
+        let received = connect("hunter2", None).await;
+        assert!(matches!(received.as_slice(), [Message::Goodbye { .. }]));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_stalled_sends() {
+        let stalled = futures::sink::unfold((), |(), _: Bytes| future::pending::<io::Result<()>>());
+        let mut client = Bounded::new(Box::pin(stalled), Duration::from_millis(10));
+        let res = send(&mut client, &Message::Heartbeat).await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, mpsc};
+
+use bytes::Bytes;
+
+use crate::DIAGNOSTICS_TARGET;
+use crate::protocol::Message;
+
+const SEGMENT_EXTENSION: &str = "tilia";
+// Frames waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 4096;
+// Frames read ahead of whoever is replaying them
+const READ_AHEAD: usize = 64;
+
+// Keeps a copy of the history in rotating segment files so it survives the process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spill {
+    pub(crate) dir: PathBuf,
+    segment_size: u64,
+    max_segments: usize,
+}
+
+impl Spill {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            segment_size: 8 * 1024 * 1024,
+            max_segments: 8,
+        }
+    }
+
+    // A new segment is started once the current one grows past this many bytes
+    pub fn with_segment_size(self, segment_size: u64) -> Self {
+        Self {
+            segment_size,
+            ..self
+        }
+    }
+
+    // The oldest segments are deleted once there are more than this
+    pub fn with_max_segments(self, max_segments: usize) -> Self {
+        Self {
+            max_segments: max_segments.max(1),
+            ..self
+        }
+    }
+}
+
+// Writes on its own thread so logging never waits on the disk
+#[derive(Clone, Debug)]
+pub(crate) struct SpillWriter {
+    tx: mpsc::SyncSender<Bytes>,
+    // Frames that didn't fit in the queue, written to the segment as a `Dropped` message
+    dropped: Arc<AtomicU64>,
+}
+
+impl SpillWriter {
+    pub(crate) fn start(spill: Spill) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        std::thread::Builder::new()
+            .name("tilia-spill".to_owned())
+            .spawn(move || {
+                // Spilling stays off for the rest of the run, the history itself isn't affected
+                if let Err(e) = write_segments(&spill, rx, &thread_dropped) {
+                    tracing::error!(
+                        target: DIAGNOSTICS_TARGET,
+                        "stopped spilling history to {}: {e}",
+                        spill.dir.display()
+                    );
+                }
+            })?;
+        Ok(Self { tx, dropped })
+    }
+
+    pub(crate) fn write(&self, frame: Bytes) {
+        if self.tx.try_send(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn write_segments(spill: &Spill, rx: mpsc::Receiver<Bytes>, dropped: &AtomicU64) -> io::Result<()> {
+    fs::create_dir_all(&spill.dir)?;
+    let mut segments = list_segments(&spill.dir)?;
+    // Start a fresh segment so a previous run's data is never overwritten
+    let mut index = segments.last().map(|(index, _)| index + 1).unwrap_or(0);
+    let (mut file, path) = open_segment(&spill.dir, index)?;
+    segments.push((index, path));
+    // Apps that restart before filling a segment would otherwise add one per run
+    trim_segments(&mut segments, spill.max_segments);
+    let mut written = 0;
+
+    while let Ok(frame) = rx.recv() {
+        let mut frame = Some(frame);
+        while let Some(next) = frame.take().or_else(|| rx.try_recv().ok()) {
+            if written >= spill.segment_size {
+                file.flush()?;
+                index += 1;
+                let (next_file, path) = open_segment(&spill.dir, index)?;
+                file = next_file;
+                segments.push((index, path));
+                written = 0;
+                trim_segments(&mut segments, spill.max_segments);
+            }
+            let count = dropped.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                let message = Message::Dropped { count }.encode();
+                write_frame(&mut file, &message)?;
+                written += 4 + message.len() as u64;
+            }
+            write_frame(&mut file, &next)?;
+            written += 4 + next.len() as u64;
+        }
+        // Only flush once the queue is drained so bursts don't turn into a write per event
+        file.flush()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_frame(out: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    out.write_all(&(frame.len() as u32).to_le_bytes())?;
+    out.write_all(frame)
+}
+
+fn trim_segments(segments: &mut Vec<(u64, PathBuf)>, max_segments: usize) {
+    while segments.len() > max_segments {
+        let (_, oldest) = segments.remove(0);
+        let _ = fs::remove_file(oldest);
+    }
+}
+
+fn open_segment(dir: &Path, index: u64) -> io::Result<(BufWriter<File>, PathBuf)> {
+    let path = dir.join(format!("{index:016}.{SEGMENT_EXTENSION}"));
+    let file = File::options().create(true).append(true).open(&path)?;
+    Ok((BufWriter::new(file), path))
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != SEGMENT_EXTENSION {
+                return None;
+            }
+            let index = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((index, path))
+        })
+        .collect();
+    segments.sort_by_key(|(index, _)| *index);
+    Ok(segments)
+}
+
+// Reads every frame in a spill directory or a dump in the replay format, oldest first. Frames are
+// read as the iterator advances, so the whole spill never has to fit in memory.
+pub fn read_segments(path: impl AsRef<Path>) -> io::Result<Frames> {
+    let path = path.as_ref();
+    let segments = if path.is_dir() {
+        list_segments(path)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect()
+    } else {
+        vec![path.to_owned()]
+    };
+    Ok(Frames {
+        segments: segments.into_iter(),
+        current: None,
+    })
+}
+
+pub struct Frames {
+    segments: std::vec::IntoIter<PathBuf>,
+    current: Option<BufReader<File>>,
+}
+
+impl Iterator for Frames {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let reader = match &mut self.current {
+                Some(reader) => reader,
+                None => match File::open(self.segments.next()?) {
+                    Ok(file) => self.current.insert(BufReader::new(file)),
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            match read_frame(reader) {
+                Ok(Some(frame)) => return Some(Ok(frame)),
+                Ok(None) => self.current = None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// `None` at the end of a segment. A partially written frame there means the process died
+// mid-write, so it's skipped.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Bytes>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u64::from(u32::from_le_bytes(len));
+    let mut frame = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut frame)?;
+    Ok((frame.len() as u64 == len).then(|| frame.into()))
+}
+
+// Reads on a blocking thread and hands the frames over as they're needed
+pub(crate) fn stream_frames(frames: Frames) -> tokio::sync::mpsc::Receiver<io::Result<Bytes>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(READ_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        for frame in frames {
+            let failed = frame.is_err();
+            if tx.blocking_send(frame).is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tilia-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn skips_a_partially_written_frame() {
+        let dir = temp_dir("partial");
+        let path = dir.join("dump.tilia");
+        let mut file = File::create(&path).unwrap();
+        write_frame(&mut file, b"first").unwrap();
+        write_frame(&mut file, b"second").unwrap();
+        file.write_all(&10u32.to_le_bytes()).unwrap();
+        file.write_all(b"cut").unwrap();
+        drop(file);
+
+        let frames: Vec<_> = read_segments(&path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(frames, [&b"first"[..], &b"second"[..]]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trims_old_segments_on_startup() {
+        let dir = temp_dir("trim");
+        for index in 0..3 {
+            open_segment(&dir, index).unwrap();
+        }
+        let (tx, rx) = mpsc::sync_channel(1);
+        tx.send(Bytes::from_static(b"frame")).unwrap();
+        drop(tx);
+        let spill = Spill::new(&dir).with_max_segments(2);
+        write_segments(&spill, rx, &AtomicU64::new(1)).unwrap();
+
+        let indexes: Vec<_> = list_segments(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(indexes, [2, 3]);
+        let frames: Vec<_> = read_segments(&dir).unwrap().map(Result::unwrap).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            Message::decode(&frames[0]).unwrap(),
+            Message::Dropped { count: 1 }
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,234 @@
-use std::sync::atomic::AtomicBool;
-use std::sync::{OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
+use arc_swap::ArcSwapOption;
 use background_service::Manager;
-use tokio::sync::Mutex;
+use background_service::error::BackgroundServiceErrors;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
 
-pub(crate) static IS_INITIALIZED: RwLock<bool> = RwLock::new(false);
-pub(crate) static IS_ENABLED: AtomicBool = AtomicBool::new(false);
-pub(crate) static HANDLE: OnceLock<Mutex<Option<Manager>>> = OnceLock::new();
+use crate::directive::Directives;
+use crate::record::Level;
+use crate::runtime::DedicatedRuntime;
+use crate::{HistoryUsage, OnDemand, history};
+
+// Starts the server for a writer on the given runtime
+pub(crate) type Starter = Box<dyn Fn(&Arc<State>, &Handle) + Send + Sync>;
+
+// Shared between a writer, its clones, its filter and the server it starts
+pub(crate) struct State {
+    is_initialized: RwLock<bool>,
+    // Set by an explicit stop so the server isn't lazily started again on the next event
+    is_stopped: AtomicBool,
+    pub(crate) handle: Mutex<Option<Manager>>,
+    // Tells connected clients to flush what they have left before the manager is cancelled
+    pub(crate) drain: Mutex<Option<CancellationToken>>,
+    shutdown_timeout: Duration,
+    // The runtime the server was last started on
+    server_runtime: Mutex<Option<Handle>>,
+    // Disabled writers have nothing to start
+    starter: Option<Starter>,
+    runtime: Option<DedicatedRuntime>,
+    history: Option<history::Sender>,
+    is_enabled: AtomicBool,
+    // Read on every event, so it's swapped rather than locked
+    capture_filter: ArcSwapOption<Directives>,
+    on_demand: Option<OnDemand>,
+    server_started: AtomicBool,
+    viewers: AtomicUsize,
+    // Notified when the last viewer disconnects
+    viewers_gone: Notify,
+}
+
+impl State {
+    pub(crate) fn new(
+        is_enabled: bool,
+        on_demand: Option<OnDemand>,
+        history: Option<history::Sender>,
+        starter: Option<Starter>,
+        runtime: Option<DedicatedRuntime>,
+        shutdown_timeout: Duration,
+    ) -> Self {
+        Self {
+            runtime,
+            history,
+            is_initialized: RwLock::new(false),
+            is_stopped: AtomicBool::new(false),
+            handle: Mutex::new(None),
+            drain: Mutex::new(None),
+            shutdown_timeout,
+            server_runtime: Mutex::new(None),
+            starter,
+            is_enabled: AtomicBool::new(is_enabled),
+            capture_filter: ArcSwapOption::empty(),
+            on_demand,
+            server_started: AtomicBool::new(false),
+            viewers: AtomicUsize::new(0),
+            viewers_gone: Notify::new(),
+        }
+    }
+
+    pub(crate) fn init(self: &Arc<Self>) -> bool {
+        let Some(starter) = &self.starter else {
+            return false;
+        };
+        if self.is_stopped.load(Ordering::SeqCst) {
+            return false;
+        }
+        // Called for every event, so the write lock is only taken when there's something to start
+        if *self.is_initialized.read().expect("Lock poisoned") {
+            return true;
+        }
+        let started = {
+            let mut is_initialized = self.is_initialized.write().expect("Lock poisoned");
+            if *is_initialized {
+                return true;
+            }
+            // Ensure we don't panic if this is called outside of the tokio runtime
+            let Some(rt) = self.runtime() else {
+                return false;
+            };
+            starter(self, &rt);
+            *self.server_runtime.lock().expect("Lock poisoned") = Some(rt);
+            *is_initialized = true;
+            true
+        };
+        if started {
+            self.server_started.store(true, Ordering::SeqCst);
+            self.rebuild_on_demand_interest();
+        }
+        started
+    }
+
+    // The dedicated runtime if there is one, otherwise whichever runtime we're called from
+    pub(crate) fn runtime(&self) -> Option<Handle> {
+        match &self.runtime {
+            Some(runtime) => Some(runtime.handle().clone()),
+            None => Handle::try_current().ok(),
+        }
+    }
+
+    pub(crate) fn server_runtime(&self) -> Option<Handle> {
+        self.server_runtime.lock().expect("Lock poisoned").clone()
+    }
+
+    pub(crate) fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    pub(crate) fn start(self: &Arc<Self>) -> bool {
+        self.is_stopped.store(false, Ordering::SeqCst);
+        self.init()
+    }
+
+    pub(crate) async fn stop(&self) -> Result<(), BackgroundServiceErrors> {
+        self.is_stopped.store(true, Ordering::SeqCst);
+        let (handle, drain) = {
+            let mut is_initialized = self.is_initialized.write().expect("Lock poisoned");
+            *is_initialized = false;
+            (
+                self.handle.lock().expect("Lock poisoned").take(),
+                self.drain.lock().expect("Lock poisoned").take(),
+            )
+        };
+        if let Some(drain) = drain {
+            drain.cancel();
+            let _ = tokio::time::timeout(self.shutdown_timeout, self.viewers_gone()).await;
+        }
+        if let Some(handle) = handle {
+            return handle.cancel().await;
+        }
+        Ok(())
+    }
+
+    async fn viewers_gone(&self) {
+        loop {
+            let notified = self.viewers_gone.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.viewers.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub(crate) fn history_usage(&self) -> HistoryUsage {
+        self.history
+            .as_ref()
+            .map(history::Sender::usage)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        *self.is_initialized.read().expect("Lock poisoned")
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.is_enabled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        // Callsites cache their interest on registration, so they need to be re-evaluated or
+        // anything registered while capture was off would stay disabled forever
+        if self.is_enabled.swap(enabled, Ordering::SeqCst) != enabled {
+            tracing::callsite::rebuild_interest_cache();
+        }
+    }
+
+    // Directives set from a connected client replace the inner filter until they're cleared
+    pub(crate) fn set_capture_filter(&self, directives: Option<Directives>) {
+        self.capture_filter.store(directives.map(Arc::new));
+        tracing::callsite::rebuild_interest_cache();
+    }
+
+    // `None` if there are no capture directives, otherwise the most verbose level they enable
+    pub(crate) fn capture_max_verbosity(&self) -> Option<Option<Level>> {
+        self.capture_filter
+            .load()
+            .as_deref()
+            .map(Directives::max_verbosity)
+    }
+
+    pub(crate) fn capture_enabled(&self, meta: &tracing::Metadata<'_>) -> Option<bool> {
+        self.capture_filter
+            .load()
+            .as_deref()
+            .map(|directives| directives.enabled(meta.target(), meta.level().into()))
+    }
+
+    // In on-demand mode, only the idle level is captured until a viewer connects
+    pub(crate) fn demand_enabled(&self, level: Level) -> bool {
+        // Events still need to reach the writer until the server has been started lazily
+        if self.viewers.load(Ordering::SeqCst) > 0 || !self.server_started.load(Ordering::SeqCst) {
+            return true;
+        }
+        match &self.on_demand {
+            Some(on_demand) => on_demand
+                .idle_level
+                .is_some_and(|idle_level| level >= idle_level),
+            None => true,
+        }
+    }
+
+    pub(crate) fn viewer_connected(&self) {
+        if self.viewers.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.rebuild_on_demand_interest();
+        }
+    }
+
+    pub(crate) fn viewer_disconnected(&self) {
+        if self.viewers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.rebuild_on_demand_interest();
+            self.viewers_gone.notify_waiters();
+        }
+    }
+
+    fn rebuild_on_demand_interest(&self) {
+        if self.on_demand.is_some() {
+            tracing::callsite::rebuild_interest_cache();
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use std::io;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use bytes::{Bytes, BytesMut};
-use futures::Future;
+use futures::{Future, Sink, Stream};
 use transport_async::Connect;
 use transport_async::codec::LengthDelimitedCodec;
 
-use crate::BoxedError;
+use crate::protocol::{Hello, Message};
+use crate::{BoxedError, spill};
 
 type StreamFuture = dyn Future<
         Output = Result<
@@ -55,10 +58,13 @@ pub mod docker {
     use bollard::Docker;
     use bollard::container::LogOutput;
     use bollard::query_parameters::LogsOptions;
-    use bytes::BytesMut;
-    use futures::{Future, Stream};
+    use bytes::{Bytes, BytesMut};
+    use futures::{Future, Sink, Stream};
     use pin_project_lite::pin_project;
 
+    use crate::protocol::{Hello, Message};
+    use crate::record::Record;
+
     pub type DockerLogStream = Pin<Box<dyn Future<Output = Result<LogStream, BoxedError>> + Send>>;
 
     #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -87,6 +93,7 @@ pub mod docker {
             );
             let stream = LogStream {
                 inner: Box::pin(logs),
+                hello_sent: false,
             };
             Box::pin(async move { Ok(stream) })
         }
@@ -97,6 +104,7 @@ pub mod docker {
         {
             #[pin]
             inner: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
+            hello_sent: bool,
         }
     }
 
@@ -107,15 +115,117 @@ pub mod docker {
             self: std::pin::Pin<&mut Self>,
             cx: &mut std::task::Context<'_>,
         ) -> std::task::Poll<Option<Self::Item>> {
-            match self.project().inner.poll_next(cx) {
-                std::task::Poll::Ready(Some(Ok(log))) => Poll::Ready(Some(Ok(BytesMut::from(
+            let this = self.project();
+            // Docker doesn't speak the tilia protocol, so act as the server side of the handshake
+            if !*this.hello_sent {
+                *this.hello_sent = true;
+                let hello = Message::Hello(Hello::current());
+                return Poll::Ready(Some(Ok(BytesMut::from(hello.encode().as_slice()))));
+            }
+            match this.inner.poll_next(cx) {
+                std::task::Poll::Ready(Some(Ok(log))) => {
                     // replace newlines to fix wonky formatting
-                    log.to_string().replace('\n', " ").as_bytes(),
-                )))),
+                    let text = log.to_string().replace('\n', " ");
+                    let record = Message::Record(Record::Text(text.into_bytes()));
+                    Poll::Ready(Some(Ok(BytesMut::from(record.encode().as_slice()))))
+                }
                 Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
                 Poll::Ready(None) => Poll::Ready(None),
                 Poll::Pending => Poll::Pending,
             }
         }
     }
+
+    // Messages from the client are discarded since there's nothing on the other end to handle them
+    impl Sink<Bytes> for LogStream {
+        type Error = bollard::errors::Error;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: Bytes) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+pub type SpillFileFuture = Pin<Box<dyn Future<Output = Result<SpillFile, BoxedError>> + Send>>;
+
+// Reads the history spilled or dumped by an app, which works even after the app has exited
+pub fn spill_file(path: impl Into<PathBuf>) -> impl Fn() -> SpillFileFuture + Clone + Send {
+    let path = path.into();
+    move || {
+        let path = path.clone();
+        Box::pin(async move {
+            let frames = tokio::task::spawn_blocking(move || spill::read_segments(path)).await??;
+            Ok(SpillFile {
+                frames: spill::stream_frames(frames),
+                hello_sent: false,
+            })
+        })
+    }
+}
+
+pub struct SpillFile {
+    frames: tokio::sync::mpsc::Receiver<io::Result<Bytes>>,
+    hello_sent: bool,
+}
+
+impl Stream for SpillFile {
+    type Item = Result<BytesMut, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // There's no server on the other end, so act as one for the handshake
+        if !self.hello_sent {
+            self.hello_sent = true;
+            let hello = Message::Hello(Hello::current());
+            return Poll::Ready(Some(Ok(BytesMut::from(hello.encode().as_slice()))));
+        }
+        match self.frames.poll_recv(cx) {
+            Poll::Ready(Some(frame)) => {
+                Poll::Ready(Some(frame.map(|frame| BytesMut::from(frame.as_ref()))))
+            }
+            // Ending the stream would make the client reconnect and replay everything again
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Commands are discarded since the files can't respond to them
+impl Sink<Bytes> for SpillFile {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, _item: Bytes) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
 }
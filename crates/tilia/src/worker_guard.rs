@@ -1,31 +1,69 @@
+use std::sync::{Arc, mpsc};
+use std::time::Duration;
+
 use background_service::error::BackgroundServiceErrors;
+use tokio::runtime::Handle;
+
+use crate::state::State;
 
-use crate::state;
+// Extra time given to cancel the server after clients have been flushed
+const CANCEL_GRACE: Duration = Duration::from_millis(500);
 
-pub struct WorkerGuard;
+// Stops the server when dropped, including one started again through a `WriterHandle`, unless the
+// guard was disarmed
+pub struct WorkerGuard {
+    state: Arc<State>,
+    armed: bool,
+}
 
 impl WorkerGuard {
+    pub(crate) fn new(state: Arc<State>) -> Self {
+        Self { state, armed: true }
+    }
+
     pub async fn stop(&mut self) -> Result<(), BackgroundServiceErrors> {
-        stop().await
+        self.state.stop().await
+    }
+
+    // For apps that don't use tokio, e.g. with a dedicated runtime. Called from inside a runtime
+    // it can't wait without risking a deadlock, so it only starts stopping the server.
+    pub fn stop_blocking(&mut self) -> Result<(), BackgroundServiceErrors> {
+        stop_blocking(self.state.clone())
+    }
+
+    // Leaves the server running once the guard is dropped, it then has to be stopped through a
+    // `WriterHandle`
+    pub fn disarm(mut self) {
+        self.armed = false;
     }
 }
 
 impl Drop for WorkerGuard {
     fn drop(&mut self) {
-        if let Ok(rt) = tokio::runtime::Handle::try_current() {
-            rt.spawn(async move {
-                let _ = stop().await;
-            });
+        if self.armed {
+            let _ = stop_blocking(self.state.clone());
         }
     }
 }
 
-async fn stop() -> Result<(), BackgroundServiceErrors> {
-    if let Some(handle) = state::HANDLE.get() {
-        let mut handle = handle.lock().await;
-        if let Some(handle) = handle.take() {
-            return handle.cancel().await;
+fn stop_blocking(state: Arc<State>) -> Result<(), BackgroundServiceErrors> {
+    // Blocking inside the runtime could deadlock it, so the flush happens in the background
+    if Handle::try_current().is_ok() {
+        if let Some(rt) = state.runtime() {
+            rt.spawn(async move {
+                let _ = state.stop().await;
+            });
         }
+        return Ok(());
     }
-    Ok(())
+    let Some(rt) = state.server_runtime() else {
+        return Ok(());
+    };
+    let timeout = state.shutdown_timeout() + CANCEL_GRACE;
+    let (done_tx, done_rx) = mpsc::channel();
+    rt.spawn(async move {
+        let _ = done_tx.send(state.stop().await);
+    });
+    // Bounded in case the runtime isn't being driven anymore
+    done_rx.recv_timeout(timeout).unwrap_or(Ok(()))
 }
@@ -1,19 +1,309 @@
 use std::fmt::Debug;
 use std::io;
-use std::sync::atomic::Ordering;
+use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::Duration;
 
 use background_service::error::BoxedError;
 use background_service::Manager;
 use bytes::{Bytes, BytesMut};
 use futures::{Future, Sink, Stream, TryStream};
-use tokio::sync::Mutex;
 use tokio_util::sync::CancellationToken;
 use tracing_subscriber::fmt::MakeWriter;
 
-use crate::server::RequestHandler;
-use crate::state::{self, HANDLE};
-use crate::{history, WorkerGuard};
+use crate::auth::Secret;
+use crate::history::EntryMeta;
+use crate::protocol::{AppInfo, Message};
+use crate::record::{Level, Record};
+use crate::runtime::DedicatedRuntime;
+use crate::server::{RequestHandler, ServerSettings};
+use crate::spill::{Spill, SpillWriter};
+use crate::state::{Starter, State};
+use crate::{DIAGNOSTICS_TARGET, Dump, WorkerGuard, WriterHandle, dump, history};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OnDemand {
+    pub(crate) idle_capacity: usize,
+    pub(crate) idle_level: Option<Level>,
+}
+
+impl Default for OnDemand {
+    fn default() -> Self {
+        Self {
+            idle_capacity: 64,
+            idle_level: Some(Level::Info),
+        }
+    }
+}
+
+impl OnDemand {
+    // Number of recent entries kept while no viewer is connected. Retention tiers keep their
+    // full capacity, so important events survive until someone connects.
+    pub fn with_idle_capacity(self, idle_capacity: usize) -> Self {
+        Self {
+            idle_capacity,
+            ..self
+        }
+    }
+
+    // Minimum level captured while no viewer is connected, `None` disables capture entirely
+    pub fn with_idle_level(self, idle_level: Option<Level>) -> Self {
+        Self { idle_level, ..self }
+    }
+}
+
+// Keeps detailed events in a private buffer that clients can query with `Command::Recorded`,
+// while only streaming events at `stream_level` and above
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlightRecorder {
+    pub(crate) stream_level: Level,
+    pub(crate) capacity: usize,
+}
+
+impl FlightRecorder {
+    pub fn new(stream_level: Level) -> Self {
+        Self {
+            stream_level,
+            capacity: 8192,
+        }
+    }
+
+    // Number of recent events kept at any level
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+}
+
+// What the server does when a client can't keep up with the rate of new events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    // Skip ahead and tell the client how many events it missed
+    #[default]
+    Skip,
+    // Close the connection
+    Disconnect,
+    // Hold up the application for up to the timeout, then skip ahead. Paused clients aren't
+    // waited for, and neither are events logged from a current thread runtime, since blocking it
+    // could stop the clients from catching up.
+    Block(Duration),
+}
+
+pub struct WriterBuilder<F> {
+    make_transport: F,
+    capacity: usize,
+    retention: Vec<(Level, usize)>,
+    max_bytes: Option<usize>,
+    max_age: Option<Duration>,
+    on_demand: Option<OnDemand>,
+    dedicated_runtime: bool,
+    slow_consumer_policy: SlowConsumerPolicy,
+    spill: Option<Spill>,
+    recorder: Option<FlightRecorder>,
+    dump: Option<Dump>,
+    shutdown_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    app_name: Option<String>,
+    app_version: Option<String>,
+    secret: Option<Secret>,
+    remote_capture_filter: bool,
+}
+
+impl<F> WriterBuilder<F> {
+    pub fn new(make_transport: F) -> Self {
+        Self {
+            make_transport,
+            capacity: 1024,
+            retention: Vec::new(),
+            max_bytes: None,
+            max_age: None,
+            on_demand: None,
+            dedicated_runtime: false,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            spill: None,
+            recorder: None,
+            dump: None,
+            shutdown_timeout: Duration::from_secs(1),
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            app_name: None,
+            app_version: None,
+            secret: None,
+            remote_capture_filter: false,
+        }
+    }
+
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+
+    // Keep the last `capacity` events at `level` and above separately, so they aren't pushed out
+    // by a flood of less important events
+    pub fn with_retention(mut self, level: Level, capacity: usize) -> Self {
+        self.retention.retain(|(existing, _)| *existing != level);
+        self.retention.push((level, capacity));
+        self
+    }
+
+    // Evict the oldest history once the retained entries use more than this many bytes, on top of
+    // the capacity. Connected clients that haven't read an evicted entry yet are told they lagged,
+    // the same as when the capacity is exceeded.
+    pub fn with_max_bytes(self, max_bytes: usize) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            ..self
+        }
+    }
+
+    // Don't replay history older than this to clients that connect later
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self {
+            max_age: Some(max_age),
+            ..self
+        }
+    }
+
+    pub fn with_on_demand(self, on_demand: OnDemand) -> Self {
+        Self {
+            on_demand: Some(on_demand),
+            ..self
+        }
+    }
+
+    // Run the server on its own thread instead of the runtime the first event is logged from, so
+    // it works in applications that don't use tokio
+    pub fn with_dedicated_runtime(self, dedicated_runtime: bool) -> Self {
+        Self {
+            dedicated_runtime,
+            ..self
+        }
+    }
+
+    pub fn with_slow_consumer_policy(self, slow_consumer_policy: SlowConsumerPolicy) -> Self {
+        Self {
+            slow_consumer_policy,
+            ..self
+        }
+    }
+
+    // Also write history to disk so it can be replayed after the app restarts
+    pub fn with_spill(self, spill: Spill) -> Self {
+        Self {
+            spill: Some(spill),
+            ..self
+        }
+    }
+
+    // The capture level is still decided by the layer's filter, so it needs to let through the
+    // detailed events for the recorder to keep them
+    pub fn with_flight_recorder(self, recorder: FlightRecorder) -> Self {
+        Self {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    pub fn with_dump(self, dump: Dump) -> Self {
+        Self {
+            dump: Some(dump),
+            ..self
+        }
+    }
+
+    // How long stopping waits for connected clients to receive the events they haven't seen yet
+    pub fn with_shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        Self {
+            shutdown_timeout,
+            ..self
+        }
+    }
+
+    // Lets clients notice a connection that died without being closed, `None` disables it
+    pub fn with_heartbeat_interval(self, heartbeat_interval: Option<Duration>) -> Self {
+        Self {
+            heartbeat_interval,
+            ..self
+        }
+    }
+
+    // Shown to clients so they can tell which app they're connected to, defaults to the name of
+    // the executable
+    pub fn with_app_name(self, app_name: impl Into<String>) -> Self {
+        Self {
+            app_name: Some(app_name.into()),
+            ..self
+        }
+    }
+
+    // Usually `env!("CARGO_PKG_VERSION")`
+    pub fn with_app_version(self, app_version: impl Into<String>) -> Self {
+        Self {
+            app_version: Some(app_version.into()),
+            ..self
+        }
+    }
+
+    // Only let clients connect if they know this secret. Failed attempts are logged as warnings
+    // under the `tilia::diagnostics` target.
+    pub fn with_secret(self, secret: Secret) -> Self {
+        Self {
+            secret: Some(secret),
+            ..self
+        }
+    }
+
+    // Let clients change which events are captured in the first place. Off by default, since it
+    // affects every client and what ends up in the history.
+    pub fn with_remote_capture_filter(self, remote_capture_filter: bool) -> Self {
+        Self {
+            remote_capture_filter,
+            ..self
+        }
+    }
+
+    // Falls back to the runtime events are logged from if the dedicated runtime can't be started,
+    // and to not spilling if the spill thread can't be, use `try_build` to handle that instead
+    pub fn build<S, I, E, Fut>(self) -> (Writer<F, S, I, E, Fut>, WorkerGuard)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = S> + Send,
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+        <I as Sink<Bytes>>::Error: Debug,
+        <I as TryStream>::Error: Debug,
+        E: Send + 'static,
+    {
+        let runtime = self
+            .dedicated_runtime
+            .then(DedicatedRuntime::new)
+            .and_then(Result::ok);
+        let spill = self
+            .spill
+            .clone()
+            .map(SpillWriter::start)
+            .and_then(Result::ok);
+        Writer::from_builder(self, runtime, spill)
+    }
+
+    pub fn try_build<S, I, E, Fut>(self) -> io::Result<Built<F, S, I, E, Fut>>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = S> + Send,
+        S: Stream<Item = Result<I, E>> + Send + 'static,
+        I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+        <I as Sink<Bytes>>::Error: Debug,
+        <I as TryStream>::Error: Debug,
+        E: Send + 'static,
+    {
+        let runtime = self
+            .dedicated_runtime
+            .then(DedicatedRuntime::new)
+            .transpose()?;
+        let spill = self.spill.clone().map(SpillWriter::start).transpose()?;
+        Ok(Writer::from_builder(self, runtime, spill))
+    }
+}
+
+// A writer together with the guard that stops its server
+pub type Built<F, S, I, E, Fut> = (Writer<F, S, I, E, Fut>, WorkerGuard);
 
 pub struct Writer<F, S, I, E, Fut>
 where
@@ -23,7 +313,9 @@ where
     I: TryStream<Ok = BytesMut> + Send + 'static,
 {
     sender: Option<history::Sender>,
-    make_transport: Arc<F>,
+    meta: Option<EntryMeta>,
+    pub(crate) state: Arc<State>,
+    _phantom: PhantomData<F>,
 }
 
 impl<F, S, I, E, Fut> Clone for Writer<F, S, I, E, Fut>
@@ -39,7 +331,9 @@ where
     fn clone(&self) -> Self {
         Self {
             sender: self.sender.clone(),
-            make_transport: self.make_transport.clone(),
+            meta: self.meta.clone(),
+            state: self.state.clone(),
+            _phantom: PhantomData,
         }
     }
 }
@@ -54,63 +348,164 @@ where
     <I as TryStream>::Error: Debug,
     E: Send + 'static,
 {
-    pub fn new(capacity: usize, make_transport: F) -> (Self, WorkerGuard) {
-        let tx = history::channel(capacity);
-        state::IS_ENABLED.swap(true, Ordering::SeqCst);
+    pub fn builder(make_transport: F) -> WriterBuilder<F> {
+        WriterBuilder::new(make_transport)
+    }
+
+    fn from_builder(
+        builder: WriterBuilder<F>,
+        runtime: Option<DedicatedRuntime>,
+        spill: Option<SpillWriter>,
+    ) -> (Self, WorkerGuard) {
+        let block_timeout = match builder.slow_consumer_policy {
+            SlowConsumerPolicy::Block(timeout) => Some(timeout),
+            SlowConsumerPolicy::Skip | SlowConsumerPolicy::Disconnect => None,
+        };
+        let tx = history::channel(history::Settings {
+            capacity: builder.capacity,
+            retention: builder.retention,
+            max_bytes: builder.max_bytes,
+            max_age: builder.max_age,
+            idle_capacity: builder.on_demand.map(|on_demand| on_demand.idle_capacity),
+            block_timeout,
+            spill,
+            recorder: builder.recorder,
+        });
+        if let Some(dump) = &builder.dump
+            && dump.on_panic()
+        {
+            dump::install_panic_hook(dump.clone(), &tx);
+        }
+        let starter = make_starter(
+            Arc::new(builder.make_transport),
+            tx.clone(),
+            ServerSettings {
+                slow_consumer_policy: builder.slow_consumer_policy,
+                spill_dir: builder.spill.map(|spill| spill.dir),
+                heartbeat_interval: builder.heartbeat_interval,
+                app_info: AppInfo::current(builder.app_name, builder.app_version),
+                secret: builder.secret,
+                remote_capture_filter: builder.remote_capture_filter,
+            },
+            builder.dump.filter(Dump::on_signal),
+        );
+        let state = Arc::new(State::new(
+            false,
+            builder.on_demand,
+            Some(tx.clone()),
+            Some(starter),
+            runtime,
+            builder.shutdown_timeout,
+        ));
+        // Flipping the flag rebuilds the interest of any callsites registered before this writer
+        state.set_enabled(true);
         (
             Self {
-                make_transport: Arc::new(make_transport),
                 sender: Some(tx),
+                meta: None,
+                state: state.clone(),
+                _phantom: PhantomData,
             },
-            WorkerGuard,
+            WorkerGuard::new(state),
         )
     }
 
-    pub fn disabled(make_transport: F) -> (Self, WorkerGuard) {
+    pub fn new(capacity: usize, make_transport: F) -> (Self, WorkerGuard) {
+        Self::builder(make_transport)
+            .with_capacity(capacity)
+            .build()
+    }
+
+    pub fn disabled(_make_transport: F) -> (Self, WorkerGuard) {
+        let state = Arc::new(State::new(false, None, None, None, None, Duration::ZERO));
         (
             Self {
-                make_transport: Arc::new(make_transport),
                 sender: None,
+                meta: None,
+                state: state.clone(),
+                _phantom: PhantomData,
             },
-            WorkerGuard,
+            WorkerGuard::new(state),
         )
     }
 
-    pub fn init(&self) -> bool {
-        let mut is_initialized = state::IS_INITIALIZED.write().expect("Lock poisoned");
-        if !*is_initialized {
-            *is_initialized = self.try_init();
-        }
-        *is_initialized
+    pub fn handle(&self) -> WriterHandle {
+        WriterHandle::new(self.state.clone())
     }
 
-    fn try_init(&self) -> bool {
-        let sender = self.sender.clone().expect("Sender not initialized");
+    pub fn init(&self) -> bool {
+        self.state.init()
+    }
+}
 
-        // Ensure we don't panic if this is called outside of the tokio runtime
-        if let Ok(rt) = tokio::runtime::Handle::try_current() {
-            let service_manager = Manager::new(
-                CancellationToken::new(),
-                background_service::Settings::default(),
-            );
-            let context = service_manager.get_context();
-            HANDLE
-                .set(Mutex::new(Some(service_manager)))
-                .expect("Handle already set");
+fn make_starter<F, S, I, E, Fut>(
+    make_transport: Arc<F>,
+    sender: history::Sender,
+    settings: ServerSettings,
+    signal_dump: Option<Dump>,
+) -> Starter
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = S> + Send,
+    S: Stream<Item = Result<I, E>> + Send + 'static,
+    I: TryStream<Ok = BytesMut> + Sink<Bytes> + Unpin + Send + 'static,
+    <I as Sink<Bytes>>::Error: Debug,
+    <I as TryStream>::Error: Debug,
+    E: Send + 'static,
+{
+    Box::new(move |state, rt| {
+        let service_manager = Manager::new(
+            CancellationToken::new(),
+            background_service::Settings::default(),
+        );
+        let context = service_manager.get_context();
+        let drain = CancellationToken::new();
+        if let Some(dump) = signal_dump.clone() {
+            let sender = sender.clone();
+            context.spawn(("dump_on_signal", move |context| {
+                dump::dump_on_signal(dump, sender, context)
+            }));
+        }
+        *state.handle.lock().expect("Lock poisoned") = Some(service_manager);
+        *state.drain.lock().expect("Lock poisoned") = Some(drain.clone());
 
-            let make_transport = self.make_transport.clone();
-            rt.spawn(async move {
-                let transport = make_transport().await;
+        let make_transport = make_transport.clone();
+        let sender = sender.clone();
+        let state = state.clone();
+        let settings = settings.clone();
+        rt.spawn(async move {
+            let transport = make_transport().await;
 
-                let server = RequestHandler::new(transport, sender.clone());
+            let server = RequestHandler::new(transport, sender, state, settings, drain);
 
-                context.spawn(server);
-                Ok::<_, BoxedError>(())
-            });
+            context.spawn(server);
+            Ok::<_, BoxedError>(())
+        });
+    })
+}
 
-            true
-        } else {
-            false
+impl<F, S, I, E, Fut> Writer<F, S, I, E, Fut>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = S>,
+    S: Stream<Item = Result<I, E>>,
+    I: TryStream<Ok = BytesMut> + Send + 'static,
+{
+    pub(crate) fn send_record(&mut self, record: Record) {
+        if let Some(sender) = &self.sender {
+            let meta = match &record {
+                Record::Event(event) => Some(EntryMeta {
+                    level: event.level,
+                    target: event.target.clone(),
+                }),
+                Record::Text(_) | Record::Binary(_) => self.meta.clone(),
+            };
+            if meta.as_ref().is_some_and(|meta| {
+                meta.target == DIAGNOSTICS_TARGET || !self.state.demand_enabled(meta.level)
+            }) {
+                return;
+            }
+            sender.send(meta, Bytes::from(Message::Record(record).encode()));
         }
     }
 }
@@ -131,6 +526,15 @@ where
         self.init();
         self.clone()
     }
+
+    fn make_writer_for(&'_ self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        let mut writer = self.make_writer();
+        writer.meta = Some(EntryMeta {
+            level: meta.level().into(),
+            target: meta.target().to_owned(),
+        });
+        writer
+    }
 }
 
 impl<F, S, I, E, Fut> io::Write for Writer<F, S, I, E, Fut>
@@ -144,10 +548,7 @@ where
     E: Send,
 {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if let Some(sender) = self.sender.as_mut() {
-            let _ = sender.send(buf.to_owned());
-        }
-
+        self.send_record(Record::output(buf.to_owned()));
         Ok(buf.len())
     }
 
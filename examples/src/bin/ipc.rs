@@ -11,7 +11,6 @@ use tilia::transport_async::codec::{CodecStream, LengthDelimitedCodec};
 use tilia::transport_async::ipc::{self, OnConflict, SecurityAttributes, ServerId};
 use tracing::{Level, debug, error, info, trace, warn};
 use tracing_subscriber::EnvFilter;
-use tracing_subscriber::fmt::Layer;
 use tracing_subscriber::prelude::*;
 
 #[tokio::main]
@@ -23,7 +22,7 @@ async fn main() -> Result<(), BoxedError> {
             .add_directive("tokio_tower=info".parse().unwrap());
 
         let name = name.to_owned();
-        let (ipc_writer, mut guard) = tilia::Writer::new(1024, move || {
+        let (ipc_layer, mut guard) = tilia::Layer::new(1024, move || {
             let name = name.to_owned();
             Box::pin(async move {
                 let transport = ipc::Endpoint::bind(
@@ -40,14 +39,10 @@ async fn main() -> Result<(), BoxedError> {
             })
         });
 
+        let ipc_filter = ipc_layer.handle().filter();
         tracing_subscriber::registry()
             .with(env_filter)
-            .with({
-                Layer::new()
-                    .compact()
-                    .with_writer(ipc_writer)
-                    .with_filter(tilia::Filter::default())
-            })
+            .with(ipc_layer.with_filter(ipc_filter))
             .init();
 
         let mut rng = rand::rng();